@@ -1,26 +1,92 @@
-use std::{env, fmt::Debug, str::FromStr};
+use std::{env, fmt::Debug, str::FromStr, time::Duration};
 
 use log::LevelFilter;
 
+use simple_http_server::{
+    compression::CompressionConfig,
+    http::RequestLimits,
+    mimetypes::MimeTypes,
+    pathresolver::SymlinkPolicy,
+    server::{
+        DEFAULTEVENTLOOPTHREADS, DEFAULTGRACEPERIOD, DEFAULTHEADERTIMEOUT, DEFAULTIP, DEFAULTKEEPALIVEMAXREQUESTS, DEFAULTKEEPALIVETIMEOUT,
+        DEFAULTMAXTHREADS, DEFAULTMINBODYRATE, DEFAULTMINTHREADS, DEFAULTPORT, DEFAULTQUEUECAPACITY, DEFAULTSERVERNAME,
+        DEFAULTTHREADIDLETIMEOUT, DEFAULTWRITETIMEOUT,
+    },
+    IoMode, OverloadPolicy,
+};
+
 //  Parameters
 const ROOTPARAMETER: &str = "--root";
 const IPPARAMETER: &str = "--ip";
 const PORTPARAMETER: &str = "--port";
 const THREADPOOLSIZEPARAMETER: &str = "--threadpoolsize";
+const MINTHREADSPARAMETER: &str = "--minthreads";
+const MAXTHREADSPARAMETER: &str = "--maxthreads";
+const THREADIDLETIMEOUTPARAMETER: &str = "--threadidletimeout";
+const QUEUECAPACITYPARAMETER: &str = "--queuecapacity";
+const OVERLOADPOLICYPARAMETER: &str = "--overloadpolicy";
+const IOMODEPARAMETER: &str = "--iomode";
+const EVENTLOOPTHREADSPARAMETER: &str = "--eventloopthreads";
 const LOGLEVELPARAMETER: &str = "--loglevel";
-const DEFAULTIP: &str = "127.0.0.1";
-const DEFAULTPORT: u16 = 4221;
-const DEFAULTTHREADPOOLSIZE: usize = 4;
+const KEEPALIVETIMEOUTPARAMETER: &str = "--keepalivetimeout";
+const KEEPALIVEMAXREQUESTSPARAMETER: &str = "--keepalivemaxrequests";
+const HEADERTIMEOUTPARAMETER: &str = "--headertimeout";
+const MINBODYRATEPARAMETER: &str = "--minbodyrate";
+const WRITETIMEOUTPARAMETER: &str = "--writetimeout";
+const MAXREQUESTLINEPARAMETER: &str = "--maxrequestline";
+const MAXHEADERSIZEPARAMETER: &str = "--maxheadersize";
+const MAXHEADERSPARAMETER: &str = "--maxheaders";
+const MAXBODYSIZEPARAMETER: &str = "--maxbodysize";
+const GRACEPERIODPARAMETER: &str = "--graceperiod";
+const SERVERNAMEPARAMETER: &str = "--servername";
+const MIMETYPESPARAMETER: &str = "--mimetypes";
+const SYMLINKSPARAMETER: &str = "--symlinks";
+const AUTOINDEXPARAMETER: &str = "--autoindex";
+const SHOWHIDDENPARAMETER: &str = "--showhidden";
+const WEAKETAGSPARAMETER: &str = "--weaketags";
+const NOCOMPRESSIONPARAMETER: &str = "--nocompression";
+const PRECOMPRESSEDPARAMETER: &str = "--precompressed";
+const COMPRESSIONMINSIZEPARAMETER: &str = "--compressionminsize";
+const COMPRESSIONTYPESPARAMETER: &str = "--compressiontypes";
+
+//  Defaults of the settings the server builder has no say in, the rest come from the library
 const DEFAULTLOGLEVEL: &str = "Info";
 
 //  The help text to display when --help is given
-static HELP: &'static str = "\
+static HELP: &str = "\
 Simple web server.\n\
 --root\t\tRoot directory to serve files from.  Required.\n\
 --ip\t\tIp address to listen on. Defaults to 127.0.0.1.\n\
 --port\t\tPort to listen on. Default to 4221.\n\
---threadpoolsize\tSize of the threadpool. Default to 4.\n\
+--minthreads\t\tWorker threads kept running when the server is idle. Defaults to 4.\n\
+--maxthreads\t\tWorker threads started while connections are waiting for one. Defaults to 64.\n\
+--threadidletimeout\tSeconds a worker above --minthreads waits for a connection before it exits. Defaults to 60.\n\
+--threadpoolsize\tFixed number of worker threads, sets both --minthreads and --maxthreads.\n\
+--queuecapacity\tConnections that may wait for a free thread. Defaults to 128.\n\
+--overloadpolicy\tWhat to do with connections once the queue is full, one of block, reject or dropoldest. Rejected and dropped connections get a 503. With --iomode eventloop block rejects, as the event loops never wait. Defaults to block.\n\
+--iomode\t\tHow connections are served, threaded gives each connection a worker thread, eventloop watches them on a few event loop threads and hands complete requests to the workers. Defaults to threaded.\n\
+--eventloopthreads\tEvent loop threads used by --iomode eventloop. Defaults to 2.\n\
 --loglevel\t\tLog level to use. Defaults to Info.\n\
+--keepalivetimeout\tSeconds an idle connection is kept open. Defaults to 5.\n\
+--keepalivemaxrequests\tRequests served per connection before closing it, at least 1. Defaults to 100.\n\
+--headertimeout\tSeconds a client has to send a request head, or its first request once connected. Answered 408 when it runs out. Defaults to 20.\n\
+--minbodyrate\t\tSlowest rate in bytes a second a request body may arrive at after its first 10 seconds, 0 for no limit. Answered 408 when it is slower. Defaults to 500.\n\
+--writetimeout\tSeconds sending a response may go without the client taking any of it before the connection is closed. Defaults to 30.\n\
+--maxrequestline\tLongest request line in bytes, longer ones are answered 414. Defaults to 8192.\n\
+--maxheadersize\tLargest header section in bytes, larger ones are answered 431. Defaults to 16384.\n\
+--maxheaders\t\tMost header fields in a request, more are answered 431. Defaults to 100.\n\
+--maxbodysize\t\tLargest request body in bytes, larger ones are answered 413. Defaults to 10485760.\n\
+--graceperiod\t\tSeconds in-flight requests are given to finish on shutdown. Defaults to 10.\n\
+--servername\t\tValue of the Server response header, empty to leave it out. Defaults to simple-http-server/<version>.\n\
+--mimetypes\t\tFile of \"extension type\" lines adding to or overriding the built in mime types.\n\
+--symlinks\t\tHow symlinks are treated, one of follow, withinroot or deny. Defaults to withinroot.\n\
+--autoindex\t\tList the contents of directories that have no index.html.\n\
+--showhidden\t\tInclude dot files in directory listings.\n\
+--weaketags\t\tSend weak ETags for files instead of strong ones.\n\
+--nocompression\t\tNever compress responses.\n\
+--compressionminsize\tSmallest body in bytes that is compressed. Defaults to 1024.\n\
+--compressiontypes\tComma separated content types to compress, a type ending in / matches all its subtypes. Defaults to text/ and common text based types.\n\
+--precompressed\t\tServe file.br, file.zst or file.gz in place of file when the client accepts that coding.\n\
 --help\t\tDisplay this help and exit.";
 
 //  Checks for the --help argument and displays the help text if found
@@ -61,13 +127,84 @@ pub fn get_port_from_args() -> Result<u16, String> {
 }
 
 //  Gets the --threadpoolsize argument and returns the value if found
-//  If the --threadpoolsize argument is not found then the default threadpoolsize is returned
-pub fn get_threadpoolsize_from_args() -> Result<usize, String> {
+//  If the --threadpoolsize argument is not found then the pool is sized by --minthreads and --maxthreads
+pub fn get_threadpoolsize_from_args() -> Result<Option<usize>, String> {
     if env::args().any(|x| x == THREADPOOLSIZEPARAMETER) {
-        return get_parameter_variable_from_args::<usize>("--threadpoolsize", "Threadpoolsize parameter given but not an usize");
+        return get_parameter_variable_from_args::<usize>("--threadpoolsize", "Threadpoolsize parameter given but not an usize").map(Some);
+    }
+
+    return Ok(None);
+}
+
+//  Gets the --minthreads argument and returns the value if found
+//  If the --minthreads argument is not found then the default minimum is returned
+pub fn get_minthreads_from_args() -> Result<usize, String> {
+    if env::args().any(|x| x == MINTHREADSPARAMETER) {
+        return get_parameter_variable_from_args::<usize>("--minthreads", "Minthreads parameter given but not an usize");
+    }
+
+    return Ok(DEFAULTMINTHREADS);
+}
+
+//  Gets the --maxthreads argument and returns the value if found
+//  If the --maxthreads argument is not found then the default maximum is returned
+pub fn get_maxthreads_from_args() -> Result<usize, String> {
+    if env::args().any(|x| x == MAXTHREADSPARAMETER) {
+        return get_parameter_variable_from_args::<usize>("--maxthreads", "Maxthreads parameter given but not an usize");
+    }
+
+    return Ok(DEFAULTMAXTHREADS);
+}
+
+//  Gets the --threadidletimeout argument and returns the value if found
+//  If the --threadidletimeout argument is not found then the default timeout is returned
+pub fn get_threadidletimeout_from_args() -> Result<Duration, String> {
+    if env::args().any(|x| x == THREADIDLETIMEOUTPARAMETER) {
+        let seconds = get_parameter_variable_from_args::<u64>("--threadidletimeout", "Threadidletimeout parameter given but not an int")?;
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    return Ok(DEFAULTTHREADIDLETIMEOUT);
+}
+
+//  Gets the --queuecapacity argument and returns the value if found
+//  If the --queuecapacity argument is not found then the default capacity is returned
+pub fn get_queuecapacity_from_args() -> Result<usize, String> {
+    if env::args().any(|x| x == QUEUECAPACITYPARAMETER) {
+        return get_parameter_variable_from_args::<usize>("--queuecapacity", "Queuecapacity parameter given but not an usize");
+    }
+
+    return Ok(DEFAULTQUEUECAPACITY);
+}
+
+//  Gets the --overloadpolicy argument and returns the value if found
+//  If the --overloadpolicy argument is not found then the default policy is returned
+pub fn get_overloadpolicy_from_args() -> Result<OverloadPolicy, String> {
+    if env::args().any(|x| x == OVERLOADPOLICYPARAMETER) {
+        return get_parameter_variable_from_args::<OverloadPolicy>("--overloadpolicy", "Overloadpolicy parameter given but not one of block, reject or dropoldest");
+    }
+
+    return Ok(OverloadPolicy::default());
+}
+
+//  Gets the --iomode argument and returns the value if found
+//  If the --iomode argument is not found then the default mode is returned
+pub fn get_iomode_from_args() -> Result<IoMode, String> {
+    if env::args().any(|x| x == IOMODEPARAMETER) {
+        return get_parameter_variable_from_args::<IoMode>("--iomode", "Iomode parameter given but not one of threaded or eventloop");
+    }
+
+    return Ok(IoMode::default());
+}
+
+//  Gets the --eventloopthreads argument and returns the value if found
+//  If the --eventloopthreads argument is not found then the default number is returned
+pub fn get_eventloopthreads_from_args() -> Result<usize, String> {
+    if env::args().any(|x| x == EVENTLOOPTHREADSPARAMETER) {
+        return get_parameter_variable_from_args::<usize>("--eventloopthreads", "Eventloopthreads parameter given but not an usize");
     }
 
-    return Ok(DEFAULTTHREADPOOLSIZE);
+    return Ok(DEFAULTEVENTLOOPTHREADS);
 }
 
 //  Gets the --loglevel argument and returns the value if found
@@ -77,7 +214,170 @@ pub fn get_loglevel_from_args() -> Result<LevelFilter, String> {
         return get_parameter_variable_from_args::<LevelFilter>("--loglevel", "Loglevel parameter given but not a LevelFilter");
     }
 
-    return Ok(DEFAULTLOGLEVEL.parse::<LevelFilter>().unwrap());
+    return DEFAULTLOGLEVEL.parse::<LevelFilter>().map_err(|e| e.to_string());
+}
+
+//  Gets the --keepalivetimeout argument and returns the value if found
+//  If the --keepalivetimeout argument is not found then the default timeout is returned
+pub fn get_keepalivetimeout_from_args() -> Result<Duration, String> {
+    if env::args().any(|x| x == KEEPALIVETIMEOUTPARAMETER) {
+        let seconds = get_parameter_variable_from_args::<u64>("--keepalivetimeout", "Keepalivetimeout parameter given but not an int")?;
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    return Ok(DEFAULTKEEPALIVETIMEOUT);
+}
+
+//  Gets the --keepalivemaxrequests argument and returns the value if found
+//  If the --keepalivemaxrequests argument is not found then the default is returned
+pub fn get_keepalivemaxrequests_from_args() -> Result<usize, String> {
+    if env::args().any(|x| x == KEEPALIVEMAXREQUESTSPARAMETER) {
+        let keepalivemaxrequests = get_parameter_variable_from_args::<usize>("--keepalivemaxrequests", "Keepalivemaxrequests parameter given but not an usize")?;
+        if keepalivemaxrequests == 0 { return Err(format!("Keepalivemaxrequests parameter must be at least 1\r\n{}", &HELP)); }
+        return Ok(keepalivemaxrequests);
+    }
+
+    return Ok(DEFAULTKEEPALIVEMAXREQUESTS);
+}
+
+//  Gets the --headertimeout argument and returns the value if found
+//  If the --headertimeout argument is not found then the default timeout is returned
+pub fn get_headertimeout_from_args() -> Result<Duration, String> {
+    if env::args().any(|x| x == HEADERTIMEOUTPARAMETER) {
+        let seconds = get_parameter_variable_from_args::<u64>("--headertimeout", "Headertimeout parameter given but not an int")?;
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    return Ok(DEFAULTHEADERTIMEOUT);
+}
+
+//  Gets the --minbodyrate argument and returns the value if found
+//  If the --minbodyrate argument is not found then the default rate is returned
+pub fn get_minbodyrate_from_args() -> Result<u64, String> {
+    if env::args().any(|x| x == MINBODYRATEPARAMETER) {
+        return get_parameter_variable_from_args::<u64>("--minbodyrate", "Minbodyrate parameter given but not an int");
+    }
+
+    return Ok(DEFAULTMINBODYRATE);
+}
+
+//  Gets the --writetimeout argument and returns the value if found
+//  If the --writetimeout argument is not found then the default timeout is returned
+pub fn get_writetimeout_from_args() -> Result<Duration, String> {
+    if env::args().any(|x| x == WRITETIMEOUTPARAMETER) {
+        let seconds = get_parameter_variable_from_args::<u64>("--writetimeout", "Writetimeout parameter given but not an int")?;
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    return Ok(DEFAULTWRITETIMEOUT);
+}
+
+//  Gets the --graceperiod argument and returns the value if found
+//  If the --graceperiod argument is not found then the default grace period is returned
+pub fn get_graceperiod_from_args() -> Result<Duration, String> {
+    if env::args().any(|x| x == GRACEPERIODPARAMETER) {
+        let seconds = get_parameter_variable_from_args::<u64>("--graceperiod", "Graceperiod parameter given but not an int")?;
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    return Ok(DEFAULTGRACEPERIOD);
+}
+
+//  Gets the --servername argument and returns the value if found
+//  If the --servername argument is not found then the default name is returned, an empty name gives None
+pub fn get_servername_from_args() -> Result<Option<String>, String> {
+    let servername = match env::args().any(|x| x == SERVERNAMEPARAMETER) {
+        true => get_parameter_variable_from_args::<String>("--servername", "Servername parameter given but not a string")?,
+        false => DEFAULTSERVERNAME.to_string(),
+    };
+
+    if servername.is_empty() { return Ok(None); }
+
+    return Ok(Some(servername));
+}
+
+//  Gets the --mimetypes argument and loads the mappings from the file if found
+//  If the --mimetypes argument is not found then only the built in mappings are used
+pub fn get_mimetypes_from_args() -> Result<MimeTypes, String> {
+    let mut mimetypes = MimeTypes::new();
+
+    if env::args().any(|x| x == MIMETYPESPARAMETER) {
+        let path = get_parameter_variable_from_args::<String>("--mimetypes", "Mimetypes parameter given but not a string")?;
+        mimetypes.load_file(&path)?;
+    }
+
+    return Ok(mimetypes);
+}
+
+//  Gets the --symlinks argument and returns the value if found
+//  If the --symlinks argument is not found then the default policy is returned
+pub fn get_symlinkpolicy_from_args() -> Result<SymlinkPolicy, String> {
+    if env::args().any(|x| x == SYMLINKSPARAMETER) {
+        return get_parameter_variable_from_args::<SymlinkPolicy>("--symlinks", "Symlinks parameter given but not one of follow, withinroot or deny");
+    }
+
+    return Ok(SymlinkPolicy::default());
+}
+
+//  Checks for the --autoindex argument
+pub fn get_autoindex_from_args() -> bool {
+    return env::args().any(|x| x == AUTOINDEXPARAMETER);
+}
+
+//  Checks for the --showhidden argument
+pub fn get_showhidden_from_args() -> bool {
+    return env::args().any(|x| x == SHOWHIDDENPARAMETER);
+}
+
+//  Checks for the --weaketags argument
+pub fn get_weaketags_from_args() -> bool {
+    return env::args().any(|x| x == WEAKETAGSPARAMETER);
+}
+
+//  Checks for the --precompressed argument
+pub fn get_precompressed_from_args() -> bool {
+    return env::args().any(|x| x == PRECOMPRESSEDPARAMETER);
+}
+
+//  Gets the --nocompression, --compressionminsize and --compressiontypes arguments
+//  Any that are not found take their default values
+pub fn get_compression_from_args() -> Result<CompressionConfig, String> {
+    let mut compression = CompressionConfig { enabled: !env::args().any(|x| x == NOCOMPRESSIONPARAMETER), ..CompressionConfig::default() };
+
+    if env::args().any(|x| x == COMPRESSIONMINSIZEPARAMETER) {
+        compression.minsize = get_parameter_variable_from_args::<u64>("--compressionminsize", "Compressionminsize parameter given but not an int")?;
+    }
+
+    if env::args().any(|x| x == COMPRESSIONTYPESPARAMETER) {
+        let mimetypes = get_parameter_variable_from_args::<String>("--compressiontypes", "Compressiontypes parameter given but not a string")?;
+        compression.mimetypes = mimetypes.split(',').map(|mimetype| mimetype.trim().to_lowercase()).filter(|mimetype| !mimetype.is_empty()).collect();
+    }
+
+    return Ok(compression);
+}
+
+//  Gets the --maxrequestline, --maxheadersize, --maxheaders and --maxbodysize arguments
+//  Any that are not found take their default values
+pub fn get_limits_from_args() -> Result<RequestLimits, String> {
+    let mut limits = RequestLimits::default();
+
+    if env::args().any(|x| x == MAXREQUESTLINEPARAMETER) {
+        limits.maxrequestline = get_parameter_variable_from_args::<usize>("--maxrequestline", "Maxrequestline parameter given but not an usize")?;
+    }
+
+    if env::args().any(|x| x == MAXHEADERSIZEPARAMETER) {
+        limits.maxheadersize = get_parameter_variable_from_args::<usize>("--maxheadersize", "Maxheadersize parameter given but not an usize")?;
+    }
+
+    if env::args().any(|x| x == MAXHEADERSPARAMETER) {
+        limits.maxheaders = get_parameter_variable_from_args::<usize>("--maxheaders", "Maxheaders parameter given but not an usize")?;
+    }
+
+    if env::args().any(|x| x == MAXBODYSIZEPARAMETER) {
+        limits.maxbodysize = get_parameter_variable_from_args::<usize>("--maxbodysize", "Maxbodysize parameter given but not an usize")?;
+    }
+
+    return Ok(limits);
 }
 
 //  Gets the value of a parameter from the command line arguments
@@ -85,18 +385,12 @@ pub fn get_loglevel_from_args() -> Result<LevelFilter, String> {
 //  if the parameter is found then the next value is returned
 fn get_parameter_variable_from_args<T: std::str::FromStr>(parameter: &str, errormessage: &str) -> Result<T, String> where <T as FromStr>::Err: Debug {
     let args = env::args().collect::<Vec<String>>();
-    let index = args.iter().position(|x| x == parameter);
-
-    if index.is_none() || index.unwrap() + 1 >= env::args().count() {
-        return Err(format!("Parameter value not found {}\r\n{}", &parameter, &HELP));
-    }
-
+    let parametervalue = args.iter().position(|x| x == parameter).and_then(|index| args.get(index + 1));
 
-    let parametervalue = args.get(index.unwrap() + 1);
     match parametervalue {
         Some(x) => match x.parse::<T>(){
             Ok(x) => return Ok(x),
-            Err(_) => return Err(format!("{}\r\n{}", errormessage.to_string(), &HELP)),
+            Err(_) => return Err(format!("{}\r\n{}", errormessage, &HELP)),
         },
         None => return Err(format!("Parameter value not found {}\r\n{}", &parameter, &HELP)),
 
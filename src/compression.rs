@@ -0,0 +1,323 @@
+use std::io::{Cursor, Read};
+
+use flate2::{read::{GzEncoder, ZlibEncoder}, Compression};
+
+use crate::http::{HttpBody, HttpRequest, HttpResponse, HttpStatusCode};
+
+//  Brotli quality and window size, kept low enough to compress on the fly
+const BROTLIQUALITY: u32 = 5;
+const BROTLIWINDOWSIZE: u32 = 22;
+const BROTLIBUFFERSIZE: usize = 8192;
+#[cfg(feature = "zstd")]
+const ZSTDLEVEL: i32 = 3;
+const DEFAULTMINSIZE: u64 = 1024;
+const DEFAULTMIMETYPES: &[&str] = &["text/", "application/javascript", "application/json", "application/ld+json", "application/manifest+json", "application/xml", "application/rss+xml", "application/atom+xml", "application/yaml", "application/toml", "application/wasm", "image/svg+xml", "image/x-icon", "font/ttf", "font/otf"];
+
+//  The content codings the server knows about
+#[derive(Clone, Copy, PartialEq)]
+pub enum ContentCoding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+impl ContentCoding {
+    //  Codings that can be produced on the fly, in the order preferred when the client values several equally
+    pub const ONTHEFLY: &'static [ContentCoding] = &[
+        Self::Brotli,
+        #[cfg(feature = "zstd")]
+        Self::Zstd,
+        Self::Gzip,
+        Self::Deflate,
+    ];
+
+    //  Codings that can be served from precompressed sibling files, in order of preference
+    pub const PRECOMPRESSED: &'static [ContentCoding] = &[
+        Self::Brotli,
+        Self::Zstd,
+        Self::Gzip,
+    ];
+
+    //  The name used in Accept-Encoding and Content-Encoding
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    //  The extension a build pipeline gives files it precompressed with this coding
+    pub fn get_file_extension(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Zstd => "zst",
+            Self::Gzip => "gz",
+            Self::Deflate => "zz",
+        }
+    }
+
+    //  Wraps the reader so it produces the encoded bytes
+    fn encode(&self, reader: Box<dyn Read + Send>) -> std::io::Result<Box<dyn Read + Send>> {
+        return Ok(match self {
+            Self::Brotli => Box::new(brotli::CompressorReader::new(reader, BROTLIBUFFERSIZE, BROTLIQUALITY, BROTLIWINDOWSIZE)),
+            #[cfg(feature = "zstd")]
+            Self::Zstd => Box::new(zstd::stream::read::Encoder::new(reader, ZSTDLEVEL)?),
+            #[cfg(not(feature = "zstd"))]
+            Self::Zstd => return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Built without zstd support")),
+            Self::Gzip => Box::new(GzEncoder::new(reader, Compression::default())),
+            //  The deflate content coding is the zlib format, not a raw deflate stream
+            Self::Deflate => Box::new(ZlibEncoder::new(reader, Compression::default())),
+        });
+    }
+}
+
+//  When and how responses are compressed
+#[derive(Clone)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    //  Bodies smaller than this are sent as they are, as compressing them gains little
+    pub minsize: u64,
+    //  Content types that are compressed, an entry ending in / matches every subtype
+    pub mimetypes: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    //  Compresses text based types of at least a kilobyte
+    fn default() -> CompressionConfig {
+        return CompressionConfig {
+            enabled: true,
+            minsize: DEFAULTMINSIZE,
+            mimetypes: DEFAULTMIMETYPES.iter().map(|mimetype| mimetype.to_string()).collect(),
+        };
+    }
+}
+
+impl CompressionConfig {
+    //  Checks the content type against the allow list, ignoring any parameters such as charset
+    fn is_compressible(&self, contenttype: &str) -> bool {
+        let essence = contenttype.split(';').next().unwrap_or_default().trim().to_lowercase();
+
+        return self.mimetypes.iter().any(|mimetype| match mimetype.ends_with('/') {
+            true => essence.starts_with(mimetype.as_str()),
+            false => essence == *mimetype,
+        });
+    }
+}
+
+//  Picks the coding the client values most from those offered, using the q-values of Accept-Encoding
+//  Ties go to whichever coding comes first in the offered list, None means the body is sent as it is
+pub fn negotiate_encoding(acceptencoding: Option<&String>, offered: &[ContentCoding]) -> Option<ContentCoding> {
+    let acceptencoding = acceptencoding?;
+    let preferences = acceptencoding.split(',').filter_map(|item| {
+        let mut parameters = item.split(';');
+        let coding = parameters.next()?.trim().to_lowercase();
+        let quality = parameters
+            .filter_map(|parameter| parameter.trim().split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .map(|(_, value)| value.trim().parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        return Some((coding, quality));
+    }).collect::<Vec<(String, f32)>>();
+
+    let get_quality = |name: &str| {
+        return preferences.iter().find(|(coding, _)| coding == name)
+            .or(preferences.iter().find(|(coding, _)| coding == "*"))
+            .map(|(_, quality)| *quality)
+            .unwrap_or(0.0);
+    };
+
+    let mut best: Option<(ContentCoding, f32)> = None;
+    for coding in offered {
+        let quality = get_quality(coding.get_name());
+        if quality > 0.0 && best.is_none_or(|(_, bestquality)| quality > bestquality) {
+            best = Some((*coding, quality));
+        }
+    }
+
+    return best.map(|(coding, _)| coding);
+}
+
+//  Adds a value to the Vary header, keeping any the handler already set
+pub fn add_vary(response: &mut HttpResponse, value: &str) {
+    let vary = match response.head.remove_header("Vary") {
+        Some(vary) if vary.split(',').any(|existing| existing.trim().eq_ignore_ascii_case(value)) => vary,
+        Some(vary) => format!("{}, {}", vary, value),
+        None => value.to_string(),
+    };
+
+    response.head.headers.insert("Vary".to_string(), vary);
+}
+
+//  Compresses the response body with the best coding the client accepts
+//  Only whole 200 responses with an allowed content type, a large enough body and no existing coding are compressed
+//  A 304 or 206 standing in for such a 200 gets the same Vary, and a 304 the same weakened ETag
+pub fn compress_response(config: &CompressionConfig, request: &HttpRequest, response: &mut HttpResponse) -> std::io::Result<()> {
+    if !config.enabled || response.head.get_header("Content-Encoding").is_some() { return Ok(()); }
+
+    //  A 304 or 206 says nothing of the whole resource in its own headers and body, the handler describes it instead
+    let representation = match response.head.status {
+        HttpStatusCode::Ok => response.head.get_header("Content-Type").map(|contenttype| (contenttype.clone(), response.body.length())),
+        HttpStatusCode::NotModified | HttpStatusCode::PartialContent => response.head.representation.as_ref()
+            .map(|representation| (representation.contenttype.clone(), Some(representation.length))),
+        _ => None,
+    };
+    let compressible = representation.is_some_and(|(contenttype, length)| config.is_compressible(&contenttype) && length.is_none_or(|length| length >= config.minsize));
+    if !compressible { return Ok(()); }
+
+    //  Whether the resource's 200 is compressed depends on Accept-Encoding, so caches must key on it
+    add_vary(response, "Accept-Encoding");
+
+    let coding = match negotiate_encoding(request.headers.get("accept-encoding"), ContentCoding::ONTHEFLY) {
+        Some(coding) => coding,
+        None => return Ok(()),
+    };
+
+    //  The client revalidating would have been sent the compressed 200, so the 304 confirms that one's ETag
+    if matches!(response.head.status, HttpStatusCode::NotModified) {
+        weaken_etag(response);
+        return Ok(());
+    }
+
+    if !matches!(response.head.status, HttpStatusCode::Ok) || response.head.get_header("Content-Range").is_some() { return Ok(()); }
+
+    let reader: Box<dyn Read + Send> = match std::mem::replace(&mut response.body, HttpBody::Empty) {
+        HttpBody::Empty => return Ok(()),
+        HttpBody::Bytes(bytes) => Box::new(Cursor::new(bytes)),
+        HttpBody::Stream(reader, _) => reader,
+    };
+
+    response.body = HttpBody::Stream(coding.encode(reader)?, None);
+    response.head.headers.insert("Content-Encoding".to_string(), coding.get_name().to_string());
+    weaken_etag(response);
+
+    return Ok(());
+}
+
+//  The encoded bytes differ from the file's, so a strong validator would no longer hold
+fn weaken_etag(response: &mut HttpResponse) {
+    if let Some(etag) = response.head.remove_header("ETag") {
+        let etag = if etag.starts_with("W/") { etag } else { format!("W/{}", etag) };
+        response.head.headers.insert("ETag".to_string(), etag);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{http::Representation, responses::{create_empty_response, create_response}};
+
+    const ETAG: &str = "\"abc\"";
+
+    fn negotiate(acceptencoding: &str) -> Option<&'static str> {
+        return negotiate_encoding(Some(&acceptencoding.to_string()), &[ContentCoding::Brotli, ContentCoding::Gzip]).map(|coding| coding.get_name());
+    }
+
+    fn compress(acceptencoding: &str, mut response: HttpResponse) -> HttpResponse {
+        let request = format!("GET / HTTP/1.1\r\nHost: x\r\nAccept-Encoding: {}", acceptencoding).parse::<HttpRequest>().unwrap();
+        response.head.headers.insert("ETag".to_string(), ETAG.to_string());
+        compress_response(&CompressionConfig::default(), &request, &mut response).unwrap();
+        return response;
+    }
+
+    fn create_file_response(status: HttpStatusCode, contenttype: &str, length: u64) -> HttpResponse {
+        let mut response = match status {
+            HttpStatusCode::NotModified => create_empty_response(&Uuid::nil(), status),
+            _ => create_response(&Uuid::nil(), status, contenttype, vec![b'a'; length as usize]),
+        };
+        response.head.representation = Some(Representation { contenttype: contenttype.to_string(), length });
+        return response;
+    }
+
+    fn get_header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+        return response.head.get_header(name).map(String::as_str);
+    }
+
+    #[test]
+    fn negotiates_by_quality() {
+        assert_eq!(negotiate("gzip;q=0.5, br;q=0.8"), Some("br"));
+        assert_eq!(negotiate("br;q=0.1, GZIP"), Some("gzip"));
+        assert_eq!(negotiate("gzip, br"), Some("br"));
+        assert_eq!(negotiate("br;q=0, *"), Some("gzip"));
+        assert_eq!(negotiate("*;q=0.2, br;q=0.1"), Some("gzip"));
+        assert_eq!(negotiate("br;q=bad, gzip;q=0"), None);
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("*;q=0"), None);
+        assert_eq!(negotiate_encoding(None, ContentCoding::ONTHEFLY).map(|coding| coding.get_name()), None);
+    }
+
+    #[test]
+    fn compresses_large_enough_text() {
+        let response = compress("gzip", create_file_response(HttpStatusCode::Ok, "text/plain; charset=utf-8", 2048));
+
+        assert_eq!(get_header(&response, "Content-Encoding"), Some("gzip"));
+        assert_eq!(get_header(&response, "Vary"), Some("Accept-Encoding"));
+        assert_eq!(get_header(&response, "ETag"), Some("W/\"abc\""));
+        assert_eq!(response.body.length(), None);
+    }
+
+    #[test]
+    fn leaves_small_and_binary_bodies_alone() {
+        for response in [create_file_response(HttpStatusCode::Ok, "text/plain", 100), create_file_response(HttpStatusCode::Ok, "image/png", 2048)] {
+            let response = compress("gzip", response);
+
+            assert_eq!(get_header(&response, "Content-Encoding"), None);
+            assert_eq!(get_header(&response, "Vary"), None);
+            assert_eq!(get_header(&response, "ETag"), Some(ETAG));
+        }
+    }
+
+    #[test]
+    fn varies_without_compressing_when_nothing_is_accepted() {
+        let response = compress("identity", create_file_response(HttpStatusCode::Ok, "text/css", 2048));
+
+        assert_eq!(get_header(&response, "Content-Encoding"), None);
+        assert_eq!(get_header(&response, "Vary"), Some("Accept-Encoding"));
+        assert_eq!(get_header(&response, "ETag"), Some(ETAG));
+    }
+
+    #[test]
+    fn treats_304_and_206_like_their_200() {
+        let response = compress("gzip", create_file_response(HttpStatusCode::NotModified, "text/css", 2048));
+        assert_eq!(get_header(&response, "Vary"), Some("Accept-Encoding"));
+        assert_eq!(get_header(&response, "ETag"), Some("W/\"abc\""));
+        assert_eq!(get_header(&response, "Content-Encoding"), None);
+
+        let response = compress("identity", create_file_response(HttpStatusCode::NotModified, "text/css", 2048));
+        assert_eq!(get_header(&response, "Vary"), Some("Accept-Encoding"));
+        assert_eq!(get_header(&response, "ETag"), Some(ETAG));
+
+        let response = compress("gzip", create_file_response(HttpStatusCode::PartialContent, "text/css", 2048));
+        assert_eq!(get_header(&response, "Vary"), Some("Accept-Encoding"));
+        assert_eq!(get_header(&response, "ETag"), Some(ETAG));
+        assert_eq!(get_header(&response, "Content-Encoding"), None);
+
+        for status in [HttpStatusCode::NotModified, HttpStatusCode::PartialContent] {
+            let response = compress("gzip", create_file_response(status, "text/css", 100));
+            assert_eq!(get_header(&response, "Vary"), None);
+            assert_eq!(get_header(&response, "ETag"), Some(ETAG));
+        }
+
+        let response = compress("gzip", create_empty_response(&Uuid::nil(), HttpStatusCode::NotModified));
+        assert_eq!(get_header(&response, "Vary"), None);
+    }
+
+    #[test]
+    fn keeps_existing_codings_and_vary_values() {
+        let mut response = create_file_response(HttpStatusCode::Ok, "text/css", 2048);
+        response.head.headers.insert("Content-Encoding".to_string(), "br".to_string());
+        let response = compress("gzip", response);
+        assert_eq!(get_header(&response, "Content-Encoding"), Some("br"));
+        assert_eq!(get_header(&response, "ETag"), Some(ETAG));
+
+        let mut response = create_file_response(HttpStatusCode::Ok, "text/css", 2048);
+        response.head.headers.insert("vary".to_string(), "Accept".to_string());
+        add_vary(&mut response, "Accept-Encoding");
+        add_vary(&mut response, "accept-encoding");
+        assert_eq!(get_header(&response, "Vary"), Some("Accept, Accept-Encoding"));
+    }
+}
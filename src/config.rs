@@ -0,0 +1,25 @@
+use std::time::Duration;
+
+use crate::http::RequestLimits;
+
+//  Connection handling settings, shared by every connection
+pub struct ServerConfig {
+    //  How long an idle persistent connection is kept open waiting for the next request
+    pub keepalivetimeout: Duration,
+    //  How long a client has to send the head of a request once it has started it
+    pub headertimeout: Duration,
+    //  Slowest rate in bytes a second a request body may arrive at, 0 for no limit
+    pub minbodyrate: u64,
+    //  How long sending a response may go without the client taking any of it
+    pub writetimeout: Duration,
+    //  How large a request may be
+    pub limits: RequestLimits,
+    //  How many requests are served on one connection before it is closed
+    pub keepalivemaxrequests: usize,
+    //  Value of the Server header added to responses, None to leave it out
+    pub servername: Option<String>,
+    //  How long in-flight requests are given to finish when the server shuts down
+    pub graceperiod: Duration,
+    //  Sent in the Retry-After header when a connection is shed because the server is overloaded
+    pub retryafter: Duration,
+}
@@ -0,0 +1,348 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::*;
+use uuid::Uuid;
+
+use crate::{
+    config::ServerConfig,
+    http::{is_timeout, HttpBody, HttpMethod, HttpReader, HttpRequest, HttpResponse, HttpStatusCode, ReadError},
+    middleware::Pipeline,
+    responses::create_empty_response,
+    shutdown::Connections,
+    threads::get_panic_message,
+    timeouts::RequestTimer,
+};
+
+//  How long sending a 503 to a rejected connection may take
+const REJECTWRITETIMEOUT: Duration = Duration::from_secs(1);
+//  How long, and for how many bytes, the rest of a refused request is discarded before the connection is closed
+pub(crate) const LINGERTIME: Duration = Duration::from_secs(5);
+pub(crate) const LINGERMAXBYTES: u64 = 64 * 1024 * 1024;
+
+//  Serves requests on the connection until either side closes it
+//  Pipelined requests are answered in the order they were received
+pub fn handle_incoming_connection(sessionid: &Uuid, config: &ServerConfig, pipeline: &Pipeline, connections: &Connections, stream: &TcpStream) {
+    let peeraddress = match stream.peer_addr() {
+        Ok(address) => address,
+        Err(e) => {
+            error!("{},Could not get peer address: {}", sessionid, e);
+            return;
+        },
+    };
+
+    info!("{},Connection from {}", sessionid, &peeraddress);
+
+    //  A client that stops taking the response must not hold the worker forever
+    if let Err(e) = stream.set_write_timeout(Some(config.writetimeout)) {
+        error!("{},Could not set write timeout: {}", sessionid, e);
+        return;
+    }
+
+    let mut reader = HttpReader::new(stream).limits(config.limits);
+    let mut writer = stream;
+
+    for requestcount in 1..=config.keepalivemaxrequests {
+        //  Whether the request was refused before all of it was read
+        let mut refused = false;
+
+        let (response, keepalive) = match read_timed_request(sessionid, &mut reader, stream, config, connections, requestcount == 1) {
+            Ok(mut request) => {
+                request.sessionid = *sessionid;
+                answer_request(sessionid, config, pipeline, connections, &request, requestcount)
+            },
+            Err(ReadError::ConnectionClosed) => break,
+            Err(ReadError::IdleTimeout) => {
+                info!("{},Closing idle connection from {}", sessionid, &peeraddress);
+                break;
+            },
+            Err(e) => {
+                error!("{},Failed to read request from {}: {}", sessionid, &peeraddress, e);
+                match answer_read_error(sessionid, config, &e) {
+                    Some(response) => {
+                        refused = true;
+                        (response, false)
+                    },
+                    None => break,
+                }
+            },
+        };
+
+        if let Err(e) = serialize_response(&mut writer, response) {
+            error!("{},Failed to send response to {}: {}", sessionid, &peeraddress, e);
+            break;
+        }
+
+        connections.set_busy(sessionid, false);
+        if refused { discard_input(sessionid, stream); }
+        if !keepalive || connections.is_shutting_down() { break; }
+    }
+
+    info!("{},Closing connection from {}", sessionid, &peeraddress);
+}
+
+//  Closing a socket with unread data resets the connection, which can throw away the response before
+//  the client reads it. So a client still sending a refused request, such as a body over the limit,
+//  is sent the end of the response and has what it sends discarded for a while before the close
+fn discard_input(sessionid: &Uuid, mut stream: &TcpStream) {
+    if stream.shutdown(Shutdown::Write).is_err() { return; }
+
+    let deadline = Instant::now() + LINGERTIME;
+    let mut chunk = [0u8; 16384];
+    let mut discarded = 0;
+
+    while discarded < LINGERMAXBYTES {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || stream.set_read_timeout(Some(remaining)).is_err() { break; }
+
+        match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => discarded += read as u64,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        }
+    }
+
+    trace!("{},Discarded {} bytes of the refused request", sessionid, discarded);
+}
+
+//  Reads the next request, giving up once it has taken longer than the timeouts allow
+//  The connection counts as busy from the first byte of the request, so a shutdown lets the rest of it arrive
+fn read_timed_request(sessionid: &Uuid, reader: &mut HttpReader<&TcpStream>, stream: &TcpStream, config: &ServerConfig, connections: &Connections, firstrequest: bool) -> Result<HttpRequest, ReadError> {
+    let mut timer = RequestTimer::new(firstrequest);
+    let mut started = false;
+
+    loop {
+        if !started && !reader.buffered().is_empty() {
+            connections.set_busy(sessionid, true);
+            started = true;
+        }
+
+        if let Some(request) = reader.take_request()? { return Ok(request); }
+
+        //  The socket times out each read on its own, so every read is given whatever time the request has left
+        let timeout = match timer.get_deadline(config, reader.buffered()) {
+            Some((deadline, expiry)) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() { return Err(expiry.get_read_error(config)); }
+                Some(remaining)
+            },
+            None => None,
+        };
+        stream.set_read_timeout(timeout)?;
+
+        match reader.fill() {
+            Ok(0) if reader.buffered().is_empty() => return Err(ReadError::ConnectionClosed),
+            Ok(0) => return Err(ReadError::UnexpectedEof),
+            Ok(_) => continue,
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => return Err(ReadError::Io(e)),
+        }
+    }
+}
+
+//  Runs the request through the pipeline and sets the headers the response goes out with
+//  Returns the response and whether the connection stays open after it
+pub(crate) fn answer_request(sessionid: &Uuid, config: &ServerConfig, pipeline: &Pipeline, connections: &Connections, request: &HttpRequest, requestcount: usize) -> (HttpResponse, bool) {
+    let (mut response, handled) = call_pipeline(sessionid, pipeline, request);
+    let framed = set_framing_headers(&mut response, request.version != "HTTP/1.0");
+    //  HEAD is answered like GET, the body is dropped here once the framing headers describe it, which is all a HEAD response sends
+    if matches!(request.method, HttpMethod::HEAD) { response.body = HttpBody::Empty; }
+    //  A server shutting down answers the request in hand but tells the client not to send another
+    let keepalive = framed && requestcount < config.keepalivemaxrequests && is_keep_alive_requested(request) && !connections.is_shutting_down() && handled;
+    set_connection_headers(config, request, &mut response, keepalive, requestcount);
+    set_automatic_headers(config, &mut response);
+
+    return (response, keepalive);
+}
+
+//  The response to a request that could not be read, after which the connection is closed
+//  Returns None when the connection is unusable and should just be dropped
+pub(crate) fn answer_read_error(sessionid: &Uuid, config: &ServerConfig, error: &ReadError) -> Option<HttpResponse> {
+    let mut response = get_read_error_response(sessionid, error)?;
+    set_framing_headers(&mut response, false);
+    response.head.headers.insert("Connection".to_string(), "close".to_string());
+    set_automatic_headers(config, &mut response);
+
+    return Some(response);
+}
+
+//  Runs the request through the middleware and handler, answering 500 if any of them panics
+//  Returns false when it panicked, as whatever state the handler left behind cannot be trusted for another request
+fn call_pipeline(sessionid: &Uuid, pipeline: &Pipeline, request: &HttpRequest) -> (HttpResponse, bool) {
+    return match panic::catch_unwind(AssertUnwindSafe(|| pipeline.handle(request))) {
+        Ok(response) => (response, true),
+        Err(panic) => {
+            error!("{},Handler panicked: {}", sessionid, get_panic_message(&panic));
+            (create_empty_response(sessionid, HttpStatusCode::InternalServerError), false)
+        },
+    };
+}
+
+//  Turns the connection away with a 503 when the server is too busy to queue it
+//  The client has not been read from, so the response goes out without waiting for its request
+pub fn reject_connection(sessionid: &Uuid, config: &ServerConfig, mut stream: &TcpStream, retryafter: Duration) {
+    warn!("{},Server overloaded, rejecting connection", sessionid);
+
+    let response = create_overload_response(sessionid, config, retryafter);
+
+    //  Shedding happens on the accepting thread, so a client that does not read must not hold it up
+    if let Err(e) = stream.set_write_timeout(Some(REJECTWRITETIMEOUT)).and_then(|_| serialize_response(&mut stream, response)) {
+        warn!("{},Could not send 503: {}", sessionid, e);
+    }
+}
+
+//  A 503 asking the client to come back later, after which the connection is closed
+pub(crate) fn create_overload_response(sessionid: &Uuid, config: &ServerConfig, retryafter: Duration) -> HttpResponse {
+    let mut response = create_empty_response(sessionid, HttpStatusCode::ServiceUnavailable);
+    response.head.headers.insert("Retry-After".to_string(), retryafter.as_secs().to_string());
+    response.head.headers.insert("Connection".to_string(), "close".to_string());
+    set_framing_headers(&mut response, false);
+    set_automatic_headers(config, &mut response);
+
+    return response;
+}
+
+//  Maps a failure to read a request onto the response sent back to the client
+//  Returns None when the connection is unusable and should just be dropped
+fn get_read_error_response(sessionid: &Uuid, error: &ReadError) -> Option<HttpResponse> {
+    return match error {
+        ReadError::Parse(e) => Some(create_empty_response(sessionid, e.get_status_code())),
+        ReadError::BadRequest(_) => Some(create_empty_response(sessionid, HttpStatusCode::BadRequest)),
+        ReadError::NotImplemented(_) => Some(create_empty_response(sessionid, HttpStatusCode::NotImplemented)),
+        ReadError::RequestTimeout(_) => Some(create_empty_response(sessionid, HttpStatusCode::RequestTimeout)),
+        ReadError::UriTooLong(_) => Some(create_empty_response(sessionid, HttpStatusCode::UriTooLong)),
+        ReadError::HeadersTooLarge(_) => Some(create_empty_response(sessionid, HttpStatusCode::RequestHeaderFieldsTooLarge)),
+        ReadError::PayloadTooLarge(_) => Some(create_empty_response(sessionid, HttpStatusCode::PayloadTooLarge)),
+        ReadError::ConnectionClosed | ReadError::UnexpectedEof | ReadError::IdleTimeout | ReadError::Io(_) => None,
+    };
+}
+
+//  Writes the response to the stream
+//  The body is sent chunked when set_framing_headers chose chunked coding
+fn serialize_response<W: Write>(stream: &mut W, response: HttpResponse) -> io::Result<()> {
+    serialize_head(stream, &response)?;
+
+    let chunked = response.head.get_header("Transfer-Encoding").is_some();
+
+    match response.body {
+        HttpBody::Empty => {},
+        HttpBody::Bytes(bytes) => stream.write_all(&bytes)?,
+        HttpBody::Stream(reader, Some(length)) => write_stream(stream, reader, length)?,
+        HttpBody::Stream(reader, None) if chunked => write_chunked(stream, reader)?,
+        HttpBody::Stream(mut reader, None) => { io::copy(&mut reader, stream)?; },
+    }
+
+    return stream.flush();
+}
+
+//  Writes the status line and headers, ending with the blank line before the body
+pub(crate) fn serialize_head<W: Write>(stream: &mut W, response: &HttpResponse) -> io::Result<()> {
+    write!(stream, "{} {}\r\n", response.head.version, response.head.status)?;
+
+    for (key, value) in response.head.headers.iter() {
+        write!(stream, "{}: {}\r\n", key, value)?;
+    }
+
+    return write!(stream, "\r\n");
+}
+
+//  Copies exactly length bytes from the reader, failing if it runs out early
+fn write_stream<W: Write>(stream: &mut W, reader: Box<dyn Read + Send>, length: u64) -> io::Result<()> {
+    let copied = io::copy(&mut reader.take(length), stream)?;
+
+    if copied < length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Body ended after {} of {} bytes", copied, length)));
+    }
+
+    return Ok(());
+}
+
+//  Copies the reader to the stream using chunked transfer coding
+fn write_chunked<W: Write>(stream: &mut W, mut reader: Box<dyn Read + Send>) -> io::Result<()> {
+    let mut chunk = [0u8; 8192];
+
+    loop {
+        let read = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        write!(stream, "{:X}\r\n", read)?;
+        stream.write_all(&chunk[..read])?;
+        write!(stream, "\r\n")?;
+    }
+
+    return write!(stream, "0\r\n\r\n");
+}
+
+//  Sets the headers telling the client where the body ends
+//  Any framing headers set by the handler are replaced as they have to match the body being sent
+//  Bodies of unknown length are sent chunked when the client allows it, otherwise they run until
+//  the connection is closed. Returns false in that case as the connection cannot be reused
+fn set_framing_headers(response: &mut HttpResponse, chunkedallowed: bool) -> bool {
+    response.head.remove_header("Content-Length");
+    response.head.remove_header("Transfer-Encoding");
+
+    //  These responses never have a body so there is nothing to frame
+    if matches!(response.head.status, HttpStatusCode::NoContent | HttpStatusCode::NotModified) {
+        response.body = HttpBody::Empty;
+        return true;
+    }
+
+    match response.body.length() {
+        _ if response.head.is_suppressed("Content-Length") => return false,
+        Some(length) => { response.head.headers.insert("Content-Length".to_string(), length.to_string()); },
+        None if chunkedallowed && !response.head.is_suppressed("Transfer-Encoding") => {
+            response.head.headers.insert("Transfer-Encoding".to_string(), "chunked".to_string());
+        },
+        None => return false,
+    }
+
+    return true;
+}
+
+//  Adds the Date and Server headers unless the handler set or suppressed them
+fn set_automatic_headers(config: &ServerConfig, response: &mut HttpResponse) {
+    if response.head.get_header("Date").is_none() && !response.head.is_suppressed("Date") {
+        response.head.headers.insert("Date".to_string(), httpdate::fmt_http_date(SystemTime::now()));
+    }
+
+    if let Some(servername) = &config.servername {
+        if response.head.get_header("Server").is_none() && !response.head.is_suppressed("Server") {
+            response.head.headers.insert("Server".to_string(), servername.to_string());
+        }
+    }
+}
+
+//  Decides whether the connection stays open after answering the request
+//  HTTP/1.1 connections persist unless the client asks to close, HTTP/1.0 only when it asks to keep alive
+fn is_keep_alive_requested(request: &HttpRequest) -> bool {
+    let connection = request.headers.get("connection").map(|value| value.to_lowercase()).unwrap_or_default();
+    let has_token = |token: &str| connection.split(',').any(|x| x.trim() == token);
+
+    if has_token("close") { return false; }
+    if request.version == "HTTP/1.0" { return has_token("keep-alive"); }
+
+    return true;
+}
+
+//  Sets the headers telling the client whether the connection stays open
+fn set_connection_headers(config: &ServerConfig, request: &HttpRequest, response: &mut HttpResponse, keepalive: bool, requestcount: usize) {
+    if !keepalive {
+        response.head.headers.insert("Connection".to_string(), "close".to_string());
+        return;
+    }
+
+    if request.version == "HTTP/1.0" {
+        response.head.headers.insert("Connection".to_string(), "keep-alive".to_string());
+        response.head.headers.insert("Keep-Alive".to_string(),
+            format!("timeout={}, max={}", config.keepalivetimeout.as_secs(), config.keepalivemaxrequests - requestcount));
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    cmp::Ordering,
+    fs,
+    io,
+    path::Path,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::http::percent_encode_segment;
+
+//  One file or directory shown in a listing
+pub struct DirectoryEntry {
+    pub name: String,
+    pub isdirectory: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+//  The column a listing is sorted by, given by the sort query parameter
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "name" => Ok(Self::Name),
+            "size" => Ok(Self::Size),
+            "mtime" => Ok(Self::Modified),
+            _ => Err("Invalid sort key".to_string()),
+        }
+    }
+}
+
+//  Reads the entries of the directory, leaving out dot files unless showhidden is set
+//  Entries whose metadata cannot be read, such as dangling symlinks, are skipped
+pub fn read_entries(path: &Path, showhidden: bool) -> io::Result<Vec<DirectoryEntry>> {
+    let mut entries = Vec::new();
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if !showhidden && name.starts_with('.') { continue; }
+
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        entries.push(DirectoryEntry {
+            name,
+            isdirectory: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+
+    return Ok(entries);
+}
+
+//  Sorts directories before files, then by the key
+pub fn sort_entries(entries: &mut [DirectoryEntry], sortkey: &SortKey, descending: bool) {
+    entries.sort_by(|a, b| {
+        let ordering = match sortkey {
+            SortKey::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        };
+        let ordering = if descending { ordering.reverse() } else { ordering };
+
+        return match (a.isdirectory, b.isdirectory) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            _ => ordering.then_with(|| a.name.cmp(&b.name)),
+        };
+    });
+}
+
+//  Renders the listing as an HTML page whose column headings re-sort it
+pub fn render_html(requestpath: &str, entries: &[DirectoryEntry], sortkey: &SortKey, descending: bool) -> String {
+    let title = format!("Index of {}", escape_html(requestpath));
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n<table>\n", title, title);
+
+    html.push_str("<tr>");
+    for (key, heading) in [(SortKey::Name, "Name"), (SortKey::Size, "Size"), (SortKey::Modified, "Last modified")] {
+        let sortname = get_sort_name(&key);
+        //  Clicking the current column flips the order, any other column sorts ascending
+        let order = if get_sort_name(sortkey) == sortname && !descending { "desc" } else { "asc" };
+        html.push_str(&format!("<th><a href=\"?sort={}&amp;order={}\">{}</a></th>", sortname, order, heading));
+    }
+    html.push_str("</tr>\n");
+
+    if requestpath != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+
+    for entry in entries {
+        let suffix = if entry.isdirectory { "/" } else { "" };
+        let size = if entry.isdirectory { "-".to_string() } else { entry.size.to_string() };
+        let modified = entry.modified.map(httpdate::fmt_http_date).unwrap_or_default();
+
+        html.push_str(&format!("<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            percent_encode_segment(&entry.name), suffix, escape_html(&entry.name), suffix, size, modified));
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    return html;
+}
+
+//  Renders the listing as JSON, modification times are seconds since the unix epoch
+pub fn render_json(requestpath: &str, entries: &[DirectoryEntry]) -> String {
+    let entries = entries.iter().map(|entry| {
+        let modified = entry.modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs().to_string())
+            .unwrap_or("null".to_string());
+        let entrytype = if entry.isdirectory { "directory" } else { "file" };
+
+        return format!("{{\"name\":\"{}\",\"type\":\"{}\",\"size\":{},\"modified\":{}}}", escape_json(&entry.name), entrytype, entry.size, modified);
+    }).collect::<Vec<String>>();
+
+    return format!("{{\"path\":\"{}\",\"entries\":[{}]}}", escape_json(requestpath), entries.join(","));
+}
+
+fn get_sort_name(sortkey: &SortKey) -> &'static str {
+    match sortkey {
+        SortKey::Name => "name",
+        SortKey::Size => "size",
+        SortKey::Modified => "mtime",
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    return value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;");
+}
+
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    return escaped;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn create_entry(name: &str, isdirectory: bool, size: u64, modified: u64) -> DirectoryEntry {
+        return DirectoryEntry { name: name.to_string(), isdirectory, size, modified: Some(UNIX_EPOCH + Duration::from_secs(modified)) };
+    }
+
+    fn create_entries() -> Vec<DirectoryEntry> {
+        return vec![create_entry("b.txt", false, 30, 200), create_entry("A.txt", false, 10, 300), create_entry("docs", true, 0, 100), create_entry("c.txt", false, 20, 100)];
+    }
+
+    fn get_names(entries: &[DirectoryEntry]) -> Vec<&str> {
+        return entries.iter().map(|entry| entry.name.as_str()).collect();
+    }
+
+    #[test]
+    fn sorts_directories_first_then_by_key() {
+        let mut entries = create_entries();
+
+        sort_entries(&mut entries, &SortKey::Name, false);
+        assert_eq!(get_names(&entries), vec!["docs", "A.txt", "b.txt", "c.txt"]);
+
+        sort_entries(&mut entries, &SortKey::Name, true);
+        assert_eq!(get_names(&entries), vec!["docs", "c.txt", "b.txt", "A.txt"]);
+
+        sort_entries(&mut entries, &SortKey::Size, false);
+        assert_eq!(get_names(&entries), vec!["docs", "A.txt", "c.txt", "b.txt"]);
+
+        sort_entries(&mut entries, &SortKey::Modified, true);
+        assert_eq!(get_names(&entries), vec!["docs", "A.txt", "b.txt", "c.txt"]);
+    }
+
+    #[test]
+    fn escapes_names_in_html() {
+        let entries = vec![create_entry("<script>alert('x')</script> & \"y\".html", false, 1, 0)];
+        let html = render_html("/a<b>/", &entries, &SortKey::Name, false);
+
+        assert!(html.contains("<title>Index of /a&lt;b&gt;/</title>"));
+        assert!(html.contains(">&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; &amp; &quot;y&quot;.html</a>"));
+        assert!(html.contains("href=\"%3Cscript%3Ealert%28%27x%27%29%3C%2Fscript%3E%20%26%20%22y%22.html\""));
+        assert!(!html.contains("<script>"));
+    }
+
+    #[test]
+    fn links_headings_to_flip_the_current_order() {
+        let html = render_html("/", &[], &SortKey::Size, false);
+
+        assert!(html.contains("href=\"?sort=size&amp;order=desc\""));
+        assert!(html.contains("href=\"?sort=name&amp;order=asc\""));
+        assert!(!html.contains("href=\"../\""));
+        assert!(render_html("/docs/", &[], &SortKey::Size, true).contains("href=\"?sort=size&amp;order=asc\""));
+        assert!(render_html("/docs/", &[], &SortKey::Name, false).contains("href=\"../\""));
+    }
+
+    #[test]
+    fn renders_json() {
+        let entries = vec![create_entry("docs", true, 0, 100), create_entry("a \"b\"\n.txt", false, 5, 200), DirectoryEntry { name: "c".to_string(), isdirectory: false, size: 1, modified: None }];
+
+        assert_eq!(render_json("/x\\y/", &entries), concat!(
+            "{\"path\":\"/x\\\\y/\",\"entries\":[",
+            "{\"name\":\"docs\",\"type\":\"directory\",\"size\":0,\"modified\":100},",
+            "{\"name\":\"a \\\"b\\\"\\n.txt\",\"type\":\"file\",\"size\":5,\"modified\":200},",
+            "{\"name\":\"c\",\"type\":\"file\",\"size\":1,\"modified\":null}]}",
+        ));
+        assert_eq!(escape_json("\u{1}"), "\\u0001");
+    }
+
+    #[test]
+    fn reads_entries_hiding_dot_files() {
+        let path = std::env::temp_dir().join(format!("directorylisting-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(path.join("sub")).unwrap();
+        fs::write(path.join("file.txt"), "hello").unwrap();
+        fs::write(path.join(".hidden"), "").unwrap();
+
+        let mut entries = read_entries(&path, false).unwrap();
+        sort_entries(&mut entries, &SortKey::Name, false);
+        assert_eq!(get_names(&entries), vec!["sub", "file.txt"]);
+        assert!(entries[0].isdirectory);
+        assert_eq!(entries[1].size, 5);
+        assert_eq!(read_entries(&path, true).unwrap().len(), 3);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn parses_sort_keys() {
+        assert!(matches!("mtime".parse::<SortKey>(), Ok(SortKey::Modified)));
+        assert!("modified".parse::<SortKey>().is_err());
+    }
+}
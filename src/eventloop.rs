@@ -0,0 +1,877 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{self, Shutdown, SocketAddr},
+    panic::{self, AssertUnwindSafe},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use log::*;
+use mio::{net::TcpStream, Events, Interest, Poll, Token, Waker};
+use uuid::Uuid;
+
+use crate::{
+    config::ServerConfig,
+    connection::{answer_read_error, answer_request, create_overload_response, serialize_head, LINGERMAXBYTES, LINGERTIME},
+    http::{HttpBody, HttpRequest, HttpResponse, RequestBuffer},
+    middleware::Pipeline,
+    shutdown::Connections,
+    threads::{get_panic_message, Job, ThreadPool},
+    timeouts::{Expiry, RequestTimer},
+};
+
+//  Token of the waker, connections are numbered from one up
+const WAKERTOKEN: Token = Token(0);
+//  Most readiness events taken from the poll at once
+const EVENTCAPACITY: usize = 1024;
+//  How many bytes are pulled from a socket or a response body per read
+const READCHUNKSIZE: usize = 16384;
+//  How much of a streamed body a worker reads at a time, the next piece is read while this much is left to send
+const BODYPIECESIZE: usize = 65536;
+//  How often connections are checked against the timeouts
+const SWEEPINTERVAL: Duration = Duration::from_secs(1);
+//  How soon a response body that had nothing to send is asked again
+const BODYPOLLINTERVAL: Duration = Duration::from_millis(20);
+
+//  How connections are served
+#[derive(Clone, Copy)]
+pub enum IoMode {
+    //  Each connection has a worker to itself from being accepted until it closes
+    Threaded,
+    //  Event loop threads watch the sockets and only hand complete requests to the workers
+    //  Idle keep-alive connections then cost a buffer rather than a thread
+    //  A streamed response body is read a piece at a time by the workers, the loop only sends what they read
+    //  A reader with nothing to send yet should return WouldBlock rather than wait, so that long lived
+    //  streams such as server sent events do not hold a worker
+    EventLoop,
+}
+
+impl Default for IoMode {
+    fn default() -> IoMode {
+        return IoMode::Threaded;
+    }
+}
+
+impl FromStr for IoMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "threaded" => Ok(Self::Threaded),
+            "eventloop" => Ok(Self::EventLoop),
+            _ => Err("Invalid io mode".to_string()),
+        }
+    }
+}
+
+enum Message {
+    //  A newly accepted connection for the loop to watch
+    Connection(Uuid, net::TcpStream),
+    //  The answer to the request a connection handed to a worker, and whether the connection stays open after it
+    Response(Token, Box<HttpResponse>, bool),
+    //  The next piece of a streamed response body, read by a worker
+    Body(Token, BodyPiece),
+    //  The job answering a connection's request or reading its body ended without a result
+    Abandoned(Token),
+    Stop,
+}
+
+//  Sends messages to an event loop, waking it up to read them
+#[derive(Clone)]
+struct LoopSender {
+    sender: Sender<Message>,
+    waker: Arc<Waker>,
+}
+
+impl LoopSender {
+    //  Gives the message back when the loop is no longer running
+    //  A loop that could not be woken still reads the message on its next sweep
+    fn send(&self, message: Message) -> Result<(), Message> {
+        self.sender.send(message).map_err(|e| e.0)?;
+        let _ = self.waker.wake();
+        return Ok(());
+    }
+}
+
+//  What an event loop shares with the jobs it hands to the workers
+struct Context {
+    config: Arc<ServerConfig>,
+    pipeline: Arc<Pipeline>,
+    connections: Arc<Connections>,
+    sender: LoopSender,
+}
+
+//  The event loop threads of a server, connections are shared between them in turn
+pub(crate) struct EventLoops {
+    senders: Vec<LoopSender>,
+    threads: Vec<JoinHandle<()>>,
+    next: AtomicUsize,
+    connections: Arc<Connections>,
+}
+
+impl EventLoops {
+    pub fn start(count: usize, config: &Arc<ServerConfig>, pipeline: &Arc<Pipeline>, connections: &Arc<Connections>, threadpool: &Arc<ThreadPool>) -> io::Result<EventLoops> {
+        let mut eventloops = EventLoops { senders: Vec::with_capacity(count), threads: Vec::with_capacity(count), next: AtomicUsize::new(0), connections: connections.clone() };
+
+        for id in 0..count {
+            let poll = Poll::new()?;
+            let waker = Arc::new(Waker::new(poll.registry(), WAKERTOKEN)?);
+            let (sender, receiver) = mpsc::channel();
+            let sender = LoopSender { sender, waker };
+
+            let eventloop = EventLoop {
+                poll,
+                receiver,
+                open: HashMap::new(),
+                nexttoken: WAKERTOKEN.0 + 1,
+                threadpool: threadpool.clone(),
+                context: Arc::new(Context { config: config.clone(), pipeline: pipeline.clone(), connections: connections.clone(), sender: sender.clone() }),
+            };
+
+            //  Loops already started are stopped by the drop when this one fails
+            let thread = thread::Builder::new().name(format!("eventloop-{}", id)).spawn(move || eventloop.run())?;
+            eventloops.senders.push(sender);
+            eventloops.threads.push(thread);
+        }
+
+        return Ok(eventloops);
+    }
+
+    //  Hands an accepted connection to the next loop
+    pub fn dispatch(&self, sessionid: Uuid, stream: net::TcpStream) {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.senders.len();
+
+        //  The connection is tracked with its own handle on the socket, so it has to be closed and forgotten here
+        if let Err(Message::Connection(sessionid, stream)) = self.senders[index].send(Message::Connection(sessionid, stream)) {
+            error!("{},Event loop {} is not running, dropping connection", sessionid, index);
+            let _ = stream.shutdown(Shutdown::Both);
+            self.connections.unregister(&sessionid);
+        }
+    }
+}
+
+impl Drop for EventLoops {
+    fn drop(&mut self) {
+        for sender in self.senders.iter() { let _ = sender.send(Message::Stop); }
+
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() { error!("{},Event loop thread panicked", Uuid::nil()); }
+        }
+    }
+}
+
+//  Where a connection is in answering its current request
+enum State {
+    //  Waiting for a complete request
+    Reading,
+    //  A worker is answering the request
+    Dispatched,
+    //  Sending the response
+    Writing,
+    //  The response to a refused request has been sent, what the client still sends is discarded
+    //  until it closes, the time runs out or this many bytes have been discarded
+    Lingering(Instant, u64),
+}
+
+//  Where the rest of a response is coming from once the output has been sent
+enum BodySource {
+    //  Nothing more to send
+    Finished,
+    //  A streamed body back with the loop, handed to a worker for its next piece when the output runs low
+    Idle(PendingBody),
+    //  A worker is reading the next piece
+    Reading,
+    //  The body had nothing to send the last time it was read, it is asked again after BODYPOLLINTERVAL
+    Waiting(PendingBody),
+}
+
+//  A response body still being read from its stream
+struct PendingBody {
+    reader: Box<dyn Read + Send>,
+    //  Bytes left to send when the length is known
+    remaining: Option<u64>,
+    chunked: bool,
+}
+
+//  What a worker read of a streamed body, and the body to read the rest from
+struct BodyPiece {
+    bytes: Vec<u8>,
+    result: io::Result<BodyRead>,
+    body: PendingBody,
+}
+
+//  What reading the next piece of a body gave
+enum BodyRead {
+    More,
+    //  The body has nothing to send yet
+    Waiting,
+    Done,
+}
+
+impl PendingBody {
+    //  Reads the next piece of the body into the output, framed as a chunk if the body is sent chunked
+    fn read_into(&mut self, output: &mut Vec<u8>) -> io::Result<BodyRead> {
+        let mut chunk = [0u8; READCHUNKSIZE];
+        let limit = match self.remaining {
+            Some(0) => return Ok(BodyRead::Done),
+            Some(remaining) => remaining.min(READCHUNKSIZE as u64) as usize,
+            None => READCHUNKSIZE,
+        };
+
+        match self.reader.read(&mut chunk[..limit]) {
+            Ok(0) => {
+                if let Some(remaining) = self.remaining {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Body ended with {} bytes unsent", remaining)));
+                }
+                if self.chunked { output.extend_from_slice(b"0\r\n\r\n"); }
+                return Ok(BodyRead::Done);
+            },
+            Ok(read) => {
+                if self.chunked { write!(output, "{:X}\r\n", read)?; }
+                output.extend_from_slice(&chunk[..read]);
+                if self.chunked { output.extend_from_slice(b"\r\n"); }
+                if let Some(remaining) = self.remaining.as_mut() { *remaining -= read as u64; }
+                return Ok(BodyRead::More);
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(BodyRead::Waiting),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(BodyRead::More),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+//  A connection watched by an event loop
+struct OpenConnection {
+    sessionid: Uuid,
+    stream: TcpStream,
+    peeraddress: SocketAddr,
+    state: State,
+    //  Bytes received and not yet taken as a request
+    input: RequestBuffer,
+    //  Whether the client has closed its side
+    readclosed: bool,
+    //  Bytes of the response waiting to be sent, from written on
+    output: Vec<u8>,
+    written: usize,
+    body: BodySource,
+    keepalive: bool,
+    //  Whether the request being answered was refused before all of it was read
+    refused: bool,
+    requestcount: usize,
+    timer: RequestTimer,
+    //  When the request being read times out, worked out again after every read
+    deadline: Option<(Instant, Expiry)>,
+    //  When the response being sent times out if the client takes none of it
+    writedeadline: Instant,
+}
+
+impl OpenConnection {
+    //  Moves the connection on as far as it can without blocking
+    //  Returns false once the connection should be closed
+    fn advance(&mut self, token: Token, context: &Arc<Context>, threadpool: &ThreadPool) -> bool {
+        loop {
+            match self.state {
+                State::Reading => {
+                    let drained = match self.read_available() {
+                        Ok(drained) => drained,
+                        Err(e) => {
+                            error!("{},Failed to read request from {}: {}", self.sessionid, &self.peeraddress, e);
+                            return false;
+                        },
+                    };
+
+                    //  The connection counts as busy from the first byte of a request, so a shutdown lets the rest of it arrive
+                    if !self.input.is_empty() { context.connections.set_busy(&self.sessionid, true); }
+
+                    match self.input.take_request() {
+                        Ok(Some(mut request)) => {
+                            self.requestcount += 1;
+                            request.sessionid = self.sessionid;
+                            self.state = State::Dispatched;
+                            //  Waiting for room in the queue would stall every connection on the loop
+                            threadpool.try_execute(RequestJob { token, request, requestcount: self.requestcount, context: context.clone() });
+                            return true;
+                        },
+                        Ok(None) if self.readclosed => {
+                            if !self.input.is_empty() { error!("{},Connection from {} closed before the request was complete", self.sessionid, &self.peeraddress); }
+                            return false;
+                        },
+                        //  Reading stopped at what the head could need, and now the body has room to arrive
+                        Ok(None) if !drained => continue,
+                        Ok(None) => {
+                            self.deadline = self.timer.get_deadline(&context.config, self.input.buffered());
+                            return true;
+                        },
+                        Err(e) => {
+                            error!("{},Failed to read request from {}: {}", self.sessionid, &self.peeraddress, e);
+                            match answer_read_error(&self.sessionid, &context.config, &e) {
+                                Some(response) => self.refuse(response, &context.config),
+                                None => return false,
+                            }
+                        },
+                    }
+                },
+                State::Dispatched => return true,
+                State::Lingering(until, discarded) => {
+                    return match self.discard_available(LINGERMAXBYTES - discarded) {
+                        Ok(Some(read)) if discarded + read < LINGERMAXBYTES => {
+                            self.state = State::Lingering(until, discarded + read);
+                            true
+                        },
+                        _ => false,
+                    };
+                },
+                State::Writing => {
+                    match self.write_available(token, context, threadpool) {
+                        Ok(true) => {},
+                        Ok(false) => return true,
+                        Err(e) => {
+                            error!("{},Failed to send response to {}: {}", self.sessionid, &self.peeraddress, e);
+                            return false;
+                        },
+                    }
+
+                    context.connections.set_busy(&self.sessionid, false);
+                    //  Closing with unread data resets the connection, which can throw away the response before the client reads it
+                    if self.refused {
+                        if self.readclosed || self.stream.shutdown(Shutdown::Write).is_err() { return false; }
+                        self.state = State::Lingering(Instant::now() + LINGERTIME, 0);
+                        continue;
+                    }
+                    if !self.keepalive || context.connections.is_shutting_down() { return false; }
+
+                    //  Pipelined requests may already be waiting in the input
+                    self.state = State::Reading;
+                    self.timer = RequestTimer::new(false);
+                },
+            }
+        }
+    }
+
+    //  Reads everything the socket has, which the edge triggered poll needs before it reports the socket again
+    //  Stops early once the input holds all the next request could need, returning false as the socket may have more
+    fn read_available(&mut self) -> io::Result<bool> {
+        let mut chunk = [0u8; READCHUNKSIZE];
+
+        while !self.readclosed {
+            if self.input.is_full() { return Ok(false); }
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.readclosed = true,
+                Ok(read) => self.input.extend(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        return Ok(true);
+    }
+
+    //  Reads and drops up to limit bytes of what the socket has, returning how many that was
+    //  Returns None once the client has closed its side
+    fn discard_available(&mut self, limit: u64) -> io::Result<Option<u64>> {
+        let mut chunk = [0u8; READCHUNKSIZE];
+        let mut discarded = 0;
+
+        while discarded < limit {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(None),
+                Ok(read) => discarded += read as u64,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        return Ok(Some(discarded));
+    }
+
+    //  Answers a request that could not be read, the connection closes once the response has been sent
+    fn refuse(&mut self, response: HttpResponse, config: &ServerConfig) {
+        self.start_response(response, false, config);
+        self.refused = true;
+    }
+
+    fn start_response(&mut self, response: HttpResponse, keepalive: bool, config: &ServerConfig) {
+        let chunked = response.head.get_header("Transfer-Encoding").is_some();
+        //  Writing to a Vec cannot fail
+        let _ = serialize_head(&mut self.output, &response);
+
+        match response.body {
+            HttpBody::Empty => {},
+            HttpBody::Bytes(bytes) => self.output.extend_from_slice(&bytes),
+            HttpBody::Stream(reader, remaining) => self.body = BodySource::Idle(PendingBody { reader, remaining, chunked }),
+        }
+
+        self.keepalive = keepalive;
+        self.state = State::Writing;
+        self.writedeadline = Instant::now() + config.writetimeout;
+    }
+
+    //  Sends as much of the response as the socket takes
+    //  Returns true once all of it has been sent
+    fn write_available(&mut self, token: Token, context: &Arc<Context>, threadpool: &ThreadPool) -> io::Result<bool> {
+        loop {
+            //  The next piece of the body is read while what is left of this one is sent
+            if self.output.len() - self.written < BODYPIECESIZE { self.read_body(token, context, threadpool); }
+            if self.written == self.output.len() { break; }
+
+            match self.stream.write(&self.output[self.written..]) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "Connection stopped taking data")),
+                Ok(written) => {
+                    self.written += written;
+                    self.writedeadline = Instant::now() + context.config.writetimeout;
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.output.clear();
+        self.written = 0;
+
+        return Ok(matches!(self.body, BodySource::Finished));
+    }
+
+    //  Hands a streamed body back with the loop to a worker to read its next piece
+    //  A full queue leaves it waiting, to be tried again after BODYPOLLINTERVAL
+    fn read_body(&mut self, token: Token, context: &Arc<Context>, threadpool: &ThreadPool) {
+        let body = match std::mem::replace(&mut self.body, BodySource::Reading) {
+            BodySource::Idle(body) => body,
+            other => {
+                self.body = other;
+                return;
+            },
+        };
+
+        threadpool.try_execute(BodyJob { token, body, context: context.clone() });
+    }
+
+    //  Queues what a worker read of the body for sending
+    //  Returns false when the body failed and the connection should be closed
+    fn receive_body(&mut self, piece: BodyPiece, writetimeout: Duration) -> bool {
+        //  A body that has only now produced something has not been waiting on the client
+        if self.written == self.output.len() { self.writedeadline = Instant::now() + writetimeout; }
+        self.output.extend_from_slice(&piece.bytes);
+
+        match piece.result {
+            Ok(BodyRead::More) => self.body = BodySource::Idle(piece.body),
+            Ok(BodyRead::Waiting) => self.body = BodySource::Waiting(piece.body),
+            Ok(BodyRead::Done) => self.body = BodySource::Finished,
+            Err(e) => {
+                error!("{},Failed to send response to {}: {}", self.sessionid, &self.peeraddress, e);
+                return false;
+            },
+        }
+
+        return true;
+    }
+
+    //  Whether the body had nothing to send the last time it was read
+    fn is_body_waiting(&self) -> bool {
+        return matches!(self.body, BodySource::Waiting(_));
+    }
+
+    //  Lets a waiting body be read again
+    fn wake_body(&mut self) {
+        self.body = match std::mem::replace(&mut self.body, BodySource::Reading) {
+            BodySource::Waiting(body) => BodySource::Idle(body),
+            other => other,
+        };
+    }
+}
+
+//  A request handed to a worker, the response is sent back to the loop that owns the connection
+struct RequestJob {
+    token: Token,
+    request: HttpRequest,
+    requestcount: usize,
+    context: Arc<Context>,
+}
+
+impl Job for RequestJob {
+    fn run(self: Box<Self>) {
+        let RequestJob { token, request, requestcount, context } = *self;
+        let reply = Reply { token, context: context.clone(), sent: false };
+        let sessionid = request.sessionid;
+
+        let (response, keepalive) = answer_request(&sessionid, &context.config, &context.pipeline, &context.connections, &request, requestcount);
+        reply.send(Message::Response(token, Box::new(response), keepalive));
+    }
+
+    fn shed(self: Box<Self>) {
+        warn!("{},Server overloaded, rejecting request", self.request.sessionid);
+        let response = create_overload_response(&self.request.sessionid, &self.context.config, self.context.config.retryafter);
+        let _ = self.context.sender.send(Message::Response(self.token, Box::new(response), false));
+    }
+}
+
+//  The next piece of a streamed body read on a worker, so file reads and compression never hold up the loop
+struct BodyJob {
+    token: Token,
+    body: PendingBody,
+    context: Arc<Context>,
+}
+
+impl Job for BodyJob {
+    fn run(self: Box<Self>) {
+        let BodyJob { token, mut body, context } = *self;
+        let reply = Reply { token, context, sent: false };
+        let mut bytes = Vec::new();
+
+        let result = loop {
+            match body.read_into(&mut bytes) {
+                Ok(BodyRead::More) if bytes.len() < BODYPIECESIZE => continue,
+                result => break result,
+            }
+        };
+
+        reply.send(Message::Body(token, BodyPiece { bytes, result, body }));
+    }
+
+    //  A response already under way cannot be answered with a 503, the body is read again once the queue has room
+    fn shed(self: Box<Self>) {
+        let BodyJob { token, body, context } = *self;
+        let _ = context.sender.send(Message::Body(token, BodyPiece { bytes: Vec::new(), result: Ok(BodyRead::Waiting), body }));
+    }
+}
+
+//  Tells the loop the connection was abandoned if the job ends without sending its result, so it is not left open forever
+struct Reply {
+    token: Token,
+    context: Arc<Context>,
+    sent: bool,
+}
+
+impl Reply {
+    fn send(mut self, message: Message) {
+        self.sent = true;
+        let _ = self.context.sender.send(message);
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        if !self.sent { let _ = self.context.sender.send(Message::Abandoned(self.token)); }
+    }
+}
+
+struct EventLoop {
+    poll: Poll,
+    receiver: Receiver<Message>,
+    open: HashMap<Token, OpenConnection>,
+    //  Tokens are never reused, so a response for a connection that has gone cannot reach a new one
+    nexttoken: usize,
+    threadpool: Arc<ThreadPool>,
+    context: Arc<Context>,
+}
+
+impl EventLoop {
+    fn run(mut self) {
+        let mut events = Events::with_capacity(EVENTCAPACITY);
+        let mut lastsweep = Instant::now();
+
+        loop {
+            let timeout = if self.open.values().any(OpenConnection::is_body_waiting) { BODYPOLLINTERVAL } else { SWEEPINTERVAL };
+
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                if e.kind() == io::ErrorKind::Interrupted { continue; }
+                error!("{},Event loop failed: {}", Uuid::nil(), e);
+                break;
+            }
+
+            for event in events.iter() {
+                if event.token() != WAKERTOKEN { self.advance(event.token()); }
+            }
+
+            if !self.receive_messages() { break; }
+
+            let waiting = self.open.iter().filter(|(_, connection)| connection.is_body_waiting()).map(|(token, _)| *token).collect::<Vec<Token>>();
+            for token in waiting {
+                if let Some(connection) = self.open.get_mut(&token) { connection.wake_body(); }
+                self.advance(token);
+            }
+
+            if lastsweep.elapsed() >= SWEEPINTERVAL {
+                self.check_timeouts();
+                lastsweep = Instant::now();
+            }
+        }
+
+        let tokens = self.open.keys().copied().collect::<Vec<Token>>();
+        for token in tokens { self.close(token); }
+    }
+
+    //  Handles everything sent to the loop, returns false when it has been told to stop
+    fn receive_messages(&mut self) -> bool {
+        while let Ok(message) = self.receiver.try_recv() {
+            match message {
+                Message::Connection(sessionid, stream) => self.add_connection(sessionid, stream),
+                Message::Response(token, response, keepalive) => {
+                    if let Some(connection) = self.open.get_mut(&token) {
+                        connection.start_response(*response, keepalive, &self.context.config);
+                        self.advance(token);
+                    }
+                },
+                Message::Body(token, piece) => {
+                    let received = match self.open.get_mut(&token) {
+                        Some(connection) => connection.receive_body(piece, self.context.config.writetimeout),
+                        None => continue,
+                    };
+                    if received { self.advance(token); } else { self.close(token); }
+                },
+                Message::Abandoned(token) => self.close(token),
+                Message::Stop => return false,
+            }
+        }
+
+        return true;
+    }
+
+    fn add_connection(&mut self, sessionid: Uuid, stream: net::TcpStream) {
+        let peeraddress = match stream.set_nonblocking(true).and_then(|_| stream.peer_addr()) {
+            Ok(address) => address,
+            Err(e) => {
+                error!("{},Could not watch connection: {}", sessionid, e);
+                self.context.connections.unregister(&sessionid);
+                return;
+            },
+        };
+
+        let token = Token(self.nexttoken);
+        self.nexttoken += 1;
+
+        let mut stream = TcpStream::from_std(stream);
+        if let Err(e) = self.poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+            error!("{},Could not watch connection: {}", sessionid, e);
+            self.context.connections.unregister(&sessionid);
+            return;
+        }
+
+        info!("{},Connection from {}", sessionid, &peeraddress);
+
+        self.open.insert(token, OpenConnection {
+            sessionid,
+            stream,
+            peeraddress,
+            state: State::Reading,
+            input: RequestBuffer::new().limits(self.context.config.limits),
+            readclosed: false,
+            output: Vec::new(),
+            written: 0,
+            body: BodySource::Finished,
+            keepalive: false,
+            refused: false,
+            requestcount: 0,
+            timer: RequestTimer::new(true),
+            deadline: None,
+            writedeadline: Instant::now(),
+        });
+
+        self.advance(token);
+    }
+
+    //  A panic parsing a request only costs its own connection, not the whole loop
+    fn advance(&mut self, token: Token) {
+        let connection = match self.open.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        let open = match panic::catch_unwind(AssertUnwindSafe(|| connection.advance(token, &self.context, &self.threadpool))) {
+            Ok(open) => open,
+            Err(panic) => {
+                error!("{},Connection from {} panicked: {}", connection.sessionid, &connection.peeraddress, get_panic_message(&panic));
+                false
+            },
+        };
+
+        if !open { self.close(token); }
+    }
+
+    //  Closes connections that have been idle too long or stopped taking their response,
+    //  and answers 408 to those whose request is arriving too slowly
+    fn check_timeouts(&mut self) {
+        let now = Instant::now();
+        let expired = self.open.iter()
+            .filter(|(_, connection)| match connection.state {
+                State::Reading => connection.deadline.is_some_and(|(deadline, _)| deadline <= now),
+                //  Only a client not taking what is ready to send times out, not a body still being read
+                State::Writing => connection.written < connection.output.len() && connection.writedeadline <= now,
+                State::Lingering(until, _) => until <= now,
+                State::Dispatched => false,
+            })
+            .map(|(token, _)| *token)
+            .collect::<Vec<Token>>();
+
+        for token in expired {
+            let connection = match self.open.get_mut(&token) {
+                Some(connection) => connection,
+                None => continue,
+            };
+
+            let expiry = match (&connection.state, connection.deadline) {
+                (State::Reading, Some((_, expiry))) => expiry,
+                (State::Lingering(..), _) => {
+                    self.close(token);
+                    continue;
+                },
+                _ => {
+                    error!("{},Failed to send response to {}: timed out", connection.sessionid, &connection.peeraddress);
+                    self.close(token);
+                    continue;
+                },
+            };
+
+            let e = expiry.get_read_error(&self.context.config);
+            match answer_read_error(&connection.sessionid, &self.context.config, &e) {
+                Some(response) => {
+                    error!("{},Failed to read request from {}: {}", connection.sessionid, &connection.peeraddress, e);
+                    connection.refuse(response, &self.context.config);
+                    self.advance(token);
+                },
+                None => {
+                    info!("{},Closing idle connection from {}", connection.sessionid, &connection.peeraddress);
+                    self.close(token);
+                },
+            }
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        let mut connection = match self.open.remove(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        let _ = self.poll.registry().deregister(&mut connection.stream);
+        info!("{},Closing connection from {}", connection.sessionid, &connection.peeraddress);
+        self.context.connections.unregister(&connection.sessionid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::BufRead,
+        net::TcpListener,
+        sync::Mutex,
+    };
+
+    use super::*;
+    use crate::{
+        http::{HttpStatusCode, RequestLimits},
+        responses::create_response,
+        threads::OverloadPolicy,
+    };
+
+    //  A body of length bytes that has nothing to send on every other read, noting the threads it is read on
+    struct RecordingReader {
+        remaining: usize,
+        waited: bool,
+        threads: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Read for RecordingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.threads.lock().unwrap().push(thread::current().name().unwrap_or_default().to_string());
+
+            self.waited = !self.waited;
+            if self.waited { return Err(io::ErrorKind::WouldBlock.into()); }
+
+            let read = buf.len().min(self.remaining);
+            buf[..read].fill(b'x');
+            self.remaining -= read;
+            return Ok(read);
+        }
+    }
+
+    fn create_config() -> ServerConfig {
+        return ServerConfig {
+            keepalivetimeout: Duration::from_secs(5),
+            headertimeout: Duration::from_secs(5),
+            minbodyrate: 0,
+            writetimeout: Duration::from_secs(5),
+            limits: RequestLimits::default(),
+            keepalivemaxrequests: 100,
+            servername: None,
+            graceperiod: Duration::from_secs(1),
+            retryafter: Duration::from_secs(1),
+        };
+    }
+
+    fn read_piece(body: &mut PendingBody) -> (String, io::Result<BodyRead>) {
+        let mut output = Vec::new();
+        let result = body.read_into(&mut output);
+        return (String::from_utf8(output).unwrap(), result);
+    }
+
+    #[test]
+    fn frames_body_pieces() {
+        let mut chunked = PendingBody { reader: Box::new(io::Cursor::new(b"hello".to_vec())), remaining: None, chunked: true };
+        assert!(matches!(read_piece(&mut chunked), (piece, Ok(BodyRead::More)) if piece == "5\r\nhello\r\n"));
+        assert!(matches!(read_piece(&mut chunked), (piece, Ok(BodyRead::Done)) if piece == "0\r\n\r\n"));
+
+        let mut sized = PendingBody { reader: Box::new(io::Cursor::new(b"hello world".to_vec())), remaining: Some(5), chunked: false };
+        assert!(matches!(read_piece(&mut sized), (piece, Ok(BodyRead::More)) if piece == "hello"));
+        assert!(matches!(read_piece(&mut sized), (piece, Ok(BodyRead::Done)) if piece.is_empty()));
+
+        let mut short = PendingBody { reader: Box::new(io::Cursor::new(b"hi".to_vec())), remaining: Some(5), chunked: false };
+        assert!(matches!(read_piece(&mut short), (_, Ok(BodyRead::More))));
+        assert!(matches!(read_piece(&mut short), (_, Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof));
+
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let mut waiting = PendingBody { reader: Box::new(RecordingReader { remaining: 1, waited: false, threads }), remaining: None, chunked: false };
+        assert!(matches!(read_piece(&mut waiting), (piece, Ok(BodyRead::Waiting)) if piece.is_empty()));
+    }
+
+    #[test]
+    fn reads_streamed_bodies_on_the_workers() {
+        const LENGTH: usize = 3 * BODYPIECESIZE + 1;
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let handler = {
+            let threads = threads.clone();
+            move |request: &HttpRequest| {
+                let reader = RecordingReader { remaining: LENGTH, waited: false, threads: threads.clone() };
+                return create_response(&request.sessionid, HttpStatusCode::Ok, "text/plain", HttpBody::stream(reader, Some(LENGTH as u64)));
+            }
+        };
+
+        let connections = Arc::new(Connections::new());
+        let threadpool = Arc::new(ThreadPool::new(2, 2, Duration::from_secs(60), 16, OverloadPolicy::Reject).unwrap());
+        let eventloops = EventLoops::start(1, &Arc::new(create_config()), &Arc::new(Pipeline::new(handler)), &connections, &threadpool).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let sessionid = Uuid::new_v4();
+        connections.register(&sessionid, &stream);
+        eventloops.dispatch(sessionid, stream);
+
+        client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut reader = io::BufReader::new(client);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") { reader.read_line(&mut head).unwrap(); }
+        let mut body = vec![0; LENGTH];
+        reader.read_exact(&mut body).unwrap();
+
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", LENGTH)));
+        assert!(body.iter().all(|byte| *byte == b'x'));
+
+        //  Every read of the body, including the ones after it had nothing to send, happened on a worker
+        let threads = threads.lock().unwrap();
+        assert!(threads.len() > 4);
+        assert!(threads.iter().all(|name| name.starts_with("worker-")), "{:?}", threads);
+    }
+}
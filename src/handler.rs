@@ -0,0 +1,12 @@
+use crate::http::{HttpRequest, HttpResponse};
+
+//  Anything that can answer a request, such as a Router, a Pipeline or a closure
+pub trait Handler: Send + Sync {
+    fn call(&self, request: &HttpRequest) -> HttpResponse;
+}
+
+impl<F> Handler for F where F: Fn(&HttpRequest) -> HttpResponse + Send + Sync {
+    fn call(&self, request: &HttpRequest) -> HttpResponse {
+        return self(request);
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+use crate::http::ReadError;
+
+const CRLF: &[u8] = b"\r\n";
+//  Longest chunk size or trailer line, so a line that never ends is not searched again on every read
+const MAXLINELENGTH: usize = 4096;
+
+//  Trailer fields that would change how the message is framed or routed are never accepted
+const FORBIDDENTRAILERS: [&str; 6] = ["content-length", "transfer-encoding", "host", "content-type", "content-encoding", "trailer"];
+
+//  A fully decoded chunked body
+pub struct ChunkedBody {
+    pub body: Vec<u8>,
+    pub trailers: HashMap<String, String>,
+    //  Number of bytes of the buffer used by the encoded body, including the trailers
+    pub consumed: usize,
+}
+
+//  What the decoder is waiting for next
+enum Stage {
+    //  A chunk size line
+    Size,
+    //  This many more bytes of chunk data
+    Data(usize),
+    //  The CRLF ending a chunk's data
+    DataEnd,
+    //  A trailer line, or the blank line ending the body
+    Trailers,
+}
+
+//  Decodes a chunked body as it arrives
+//  How far it got is kept between calls, so every byte of the body is only looked at once
+pub struct ChunkedDecoder {
+    maxbodysize: usize,
+    stage: Stage,
+    //  Bytes of the encoded body decoded so far
+    position: usize,
+    body: Vec<u8>,
+    trailers: HashMap<String, String>,
+}
+
+impl ChunkedDecoder {
+    pub fn new(maxbodysize: usize) -> ChunkedDecoder {
+        return ChunkedDecoder { maxbodysize, stage: Stage::Size, position: 0, body: Vec::new(), trailers: HashMap::new() };
+    }
+
+    //  The most bytes the encoded body can take up before it is either complete or refused
+    pub fn get_max_encoded_length(&self) -> usize {
+        return self.maxbodysize + MAXLINELENGTH + CRLF.len();
+    }
+
+    //  Decodes what has arrived of the encoded body since the last call
+    //  The encoded body is everything received after the head, including the bytes earlier calls have seen
+    //  Returns None when more bytes are needed before the body is complete
+    //  A body is refused as soon as it is encoded in more than maxbodysize bytes, or a chunk would take it over
+    pub fn decode(&mut self, encoded: &[u8]) -> Result<Option<ChunkedBody>, ReadError> {
+        loop {
+            if self.position > self.maxbodysize {
+                return Err(ReadError::PayloadTooLarge(format!("Chunked body over {} bytes", self.maxbodysize)));
+            }
+
+            match self.stage {
+                Stage::Size => {
+                    let line = match read_line(encoded, self.position)? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    self.position += line.len() + CRLF.len();
+
+                    let size = parse_chunk_size(line).map_err(ReadError::BadRequest)?;
+
+                    if size > self.maxbodysize - self.body.len() {
+                        return Err(ReadError::PayloadTooLarge(format!("Chunked body over {} bytes", self.maxbodysize)));
+                    }
+
+                    self.stage = if size == 0 { Stage::Trailers } else { Stage::Data(size) };
+                },
+                Stage::Data(remaining) => {
+                    let available = (encoded.len() - self.position).min(remaining);
+                    if available == 0 { return Ok(None); }
+
+                    self.body.extend_from_slice(&encoded[self.position..self.position + available]);
+                    self.position += available;
+                    self.stage = if available == remaining { Stage::DataEnd } else { Stage::Data(remaining - available) };
+                },
+                Stage::DataEnd => {
+                    if encoded.len() < self.position + CRLF.len() { return Ok(None); }
+                    if &encoded[self.position..self.position + CRLF.len()] != CRLF {
+                        return Err(ReadError::BadRequest("Chunk data not followed by CRLF".to_string()));
+                    }
+
+                    self.position += CRLF.len();
+                    self.stage = Stage::Size;
+                },
+                Stage::Trailers => {
+                    let line = match read_line(encoded, self.position)? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    self.position += line.len() + CRLF.len();
+
+                    if line.is_empty() {
+                        return Ok(Some(ChunkedBody { body: std::mem::take(&mut self.body), trailers: std::mem::take(&mut self.trailers), consumed: self.position }));
+                    }
+
+                    self.add_trailer(line)?;
+                },
+            }
+        }
+    }
+
+    fn add_trailer(&mut self, line: &[u8]) -> Result<(), ReadError> {
+        let line = std::str::from_utf8(line).map_err(|_| ReadError::BadRequest("Trailer is not valid UTF-8".to_string()))?;
+        let (name, value) = line.split_once(':').ok_or_else(|| ReadError::BadRequest(format!("Invalid trailer {}", line)))?;
+        let name = name.trim().to_lowercase();
+
+        if FORBIDDENTRAILERS.contains(&name.as_str()) { return Ok(()); }
+
+        self.trailers.insert(name, value.trim().to_string());
+        return Ok(());
+    }
+}
+
+//  Gets the line starting at the position, without its CRLF
+//  Returns None when the line has not all arrived yet
+fn read_line(buffer: &[u8], position: usize) -> Result<Option<&[u8]>, ReadError> {
+    let searched = &buffer[position..buffer.len().min(position + MAXLINELENGTH + CRLF.len())];
+
+    return match searched.windows(CRLF.len()).position(|window| window == CRLF) {
+        Some(length) => Ok(Some(&buffer[position..position + length])),
+        None if searched.len() > MAXLINELENGTH => Err(ReadError::BadRequest(format!("Chunked body line over {} bytes", MAXLINELENGTH))),
+        None => Ok(None),
+    };
+}
+
+//  Parses a chunk size line, validating and discarding any chunk extensions
+//  chunk-size [ *( BWS ";" BWS ext-name [ BWS "=" BWS ext-val ] ) ]
+fn parse_chunk_size(line: &[u8]) -> Result<usize, String> {
+    let line = std::str::from_utf8(line).map_err(|_| "Chunk size is not valid UTF-8".to_string())?;
+    let mut parts = line.split(';');
+    let size = parts.next().unwrap_or_default().trim_end_matches([' ', '\t']);
+
+    if size.is_empty() || !size.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid chunk size {}", size));
+    }
+
+    for extension in parts {
+        let (name, value) = match extension.split_once('=') {
+            Some((name, value)) => (name.trim_matches([' ', '\t']), Some(value.trim_matches([' ', '\t']))),
+            None => (extension.trim_matches([' ', '\t']), None),
+        };
+
+        if !is_token(name) { return Err(format!("Invalid chunk extension {}", extension)); }
+        if let Some(value) = value {
+            if !is_token(value) && !is_quoted_string(value) { return Err(format!("Invalid chunk extension {}", extension)); }
+        }
+    }
+
+    return usize::from_str_radix(size, 16).map_err(|_| format!("Chunk size too large {}", size));
+}
+
+fn is_token(value: &str) -> bool {
+    return !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+}
+
+fn is_quoted_string(value: &str) -> bool {
+    return value.len() >= 2 && value.starts_with('"') && value.ends_with('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAXBODYSIZE: usize = 1024;
+
+    fn decode(encoded: &[u8]) -> Result<Option<ChunkedBody>, ReadError> {
+        return ChunkedDecoder::new(MAXBODYSIZE).decode(encoded);
+    }
+
+    #[test]
+    fn decodes_chunks_extensions_and_trailers() {
+        let encoded = b"5;name=value\r\nhello\r\n6 ; quoted=\"a b\"\r\n world\r\n0\r\nX-Checksum: abc\r\nContent-Length: 5\r\n\r\nNEXT";
+        let chunkedbody = decode(encoded).unwrap().unwrap();
+
+        assert_eq!(chunkedbody.body, b"hello world");
+        assert_eq!(chunkedbody.trailers.get("x-checksum").map(String::as_str), Some("abc"));
+        assert!(!chunkedbody.trailers.contains_key("content-length"));
+        assert_eq!(&encoded[chunkedbody.consumed..], b"NEXT");
+    }
+
+    #[test]
+    fn decodes_a_body_arriving_a_byte_at_a_time() {
+        let encoded = b"3\r\nabc\r\na\r\n0123456789\r\n0\r\n\r\n";
+        let mut decoder = ChunkedDecoder::new(MAXBODYSIZE);
+
+        for received in 1..encoded.len() {
+            assert!(decoder.decode(&encoded[..received]).unwrap().is_none());
+        }
+
+        let chunkedbody = decoder.decode(encoded).unwrap().unwrap();
+        assert_eq!(chunkedbody.body, b"abc0123456789");
+        assert_eq!(chunkedbody.consumed, encoded.len());
+    }
+
+    #[test]
+    fn refuses_chunk_sizes_over_the_limit_without_overflowing() {
+        assert!(matches!(decode(b"ffffffffffffffec\r\nabc"), Err(ReadError::PayloadTooLarge(_))));
+        assert!(matches!(decode(b"401\r\n"), Err(ReadError::PayloadTooLarge(_))));
+        assert!(matches!(decode(b"200\r\n"), Ok(None)));
+        assert!(matches!(decode(b"fffffffffffffffffffff\r\n"), Err(ReadError::BadRequest(_))));
+    }
+
+    #[test]
+    fn refuses_bodies_over_the_limit_across_chunks() {
+        let chunk = format!("200\r\n{}\r\n", "a".repeat(0x200));
+        assert!(decode(format!("{}200\r\n", chunk).as_bytes()).unwrap().is_none());
+        assert!(matches!(decode(format!("{}201\r\n", chunk).as_bytes()), Err(ReadError::PayloadTooLarge(_))));
+
+        assert!(matches!(decode("1\r\na\r\n".repeat(MAXBODYSIZE / 6 + 1).as_bytes()), Err(ReadError::PayloadTooLarge(_))));
+    }
+
+    #[test]
+    fn rejects_malformed_bodies() {
+        assert!(matches!(decode(b"x\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(decode(b"\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(decode(b"-1\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(decode(b"3;bad ext\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(decode(b"3\r\nabcd\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(decode(b"0\r\nnocolon\r\n\r\n"), Err(ReadError::BadRequest(_))));
+    }
+
+    #[test]
+    fn refuses_lines_that_never_end() {
+        let line = "1".repeat(MAXLINELENGTH + 1);
+        assert!(matches!(ChunkedDecoder::new(usize::MAX).decode(line.as_bytes()), Err(ReadError::BadRequest(_))));
+        assert!(matches!(ChunkedDecoder::new(usize::MAX).decode(&line.as_bytes()[..MAXLINELENGTH]), Ok(None)));
+    }
+}
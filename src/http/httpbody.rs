@@ -0,0 +1,75 @@
+use std::io::{self, Read};
+
+//  The body of a response
+//  Bodies are raw bytes so binary content such as images can be sent unchanged
+pub enum HttpBody {
+    Empty,
+    Bytes(Vec<u8>),
+    //  A body read from the reader while the response is being sent, so it is never held in memory
+    //  When the length is known it is sent as Content-Length, otherwise the body is sent chunked
+    Stream(Box<dyn Read + Send>, Option<u64>),
+}
+
+impl HttpBody {
+    pub fn stream(reader: impl Read + Send + 'static, length: Option<u64>) -> HttpBody {
+        return Self::Stream(Box::new(reader), length);
+    }
+
+    //  Creates a body sent chunk by chunk as the iterator produces them
+    pub fn from_chunks<I>(chunks: I) -> HttpBody
+    where I: Iterator<Item = Vec<u8>> + Send + 'static, {
+        return Self::stream(ChunkReader { chunks, current: io::Cursor::new(Vec::new()) }, None);
+    }
+
+    //  Number of bytes in the body, None for a stream of unknown length
+    pub fn length(&self) -> Option<u64> {
+        match self {
+            Self::Empty => Some(0),
+            Self::Bytes(bytes) => Some(bytes.len() as u64),
+            Self::Stream(_, length) => *length,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.length() == Some(0);
+    }
+}
+
+impl From<Vec<u8>> for HttpBody {
+    fn from(bytes: Vec<u8>) -> Self {
+        if bytes.is_empty() { return Self::Empty; }
+        return Self::Bytes(bytes);
+    }
+}
+
+impl From<String> for HttpBody {
+    fn from(text: String) -> Self {
+        return Self::from(text.into_bytes());
+    }
+}
+
+impl From<&str> for HttpBody {
+    fn from(text: &str) -> Self {
+        return Self::from(text.as_bytes().to_vec());
+    }
+}
+
+//  Adapts an iterator of byte chunks into a reader
+struct ChunkReader<I: Iterator<Item = Vec<u8>>> {
+    chunks: I,
+    current: io::Cursor<Vec<u8>>,
+}
+
+impl<I: Iterator<Item = Vec<u8>>> Read for ChunkReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() { return Ok(read); }
+
+            match self.chunks.next() {
+                Some(chunk) => self.current = io::Cursor::new(chunk),
+                None => return Ok(0),
+            }
+        }
+    }
+}
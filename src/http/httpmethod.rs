@@ -3,6 +3,9 @@ use std::{
     fmt::{Display, Formatter}
 };
 
+//  Variants are named after the method tokens as they appear on the wire
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum HttpMethod {
     GET,
     POST,
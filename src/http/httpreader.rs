@@ -0,0 +1,444 @@
+use std::{
+    fmt::{Display, Formatter},
+    io::{self, Read},
+};
+
+use crate::http::{
+    chunkeddecoder::ChunkedDecoder,
+    requestparser::{check_line_endings, parse_request_head},
+    HttpRequest, ParseError,
+};
+
+//  How many bytes are pulled from the stream per read
+const READCHUNKSIZE: usize = 8192;
+const HEADTERMINATOR: &[u8] = b"\r\n\r\n";
+const CRLF: &[u8] = b"\r\n";
+const DEFAULTMAXREQUESTLINE: usize = 8192;
+const DEFAULTMAXHEADERSIZE: usize = 16384;
+const DEFAULTMAXHEADERS: usize = 100;
+const DEFAULTMAXBODYSIZE: usize = 10 * 1024 * 1024;
+
+//  How large a request may be, checked as it arrives so an oversized one is refused before it is all held in memory
+#[derive(Clone, Copy)]
+pub struct RequestLimits {
+    //  Longest request line in bytes, answered 414
+    pub maxrequestline: usize,
+    //  Largest header section in bytes, answered 431
+    pub maxheadersize: usize,
+    //  Most header fields, answered 431
+    pub maxheaders: usize,
+    //  Largest body in bytes, answered 413. A chunked body is measured as sent, chunk sizes and trailers included
+    pub maxbodysize: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> RequestLimits {
+        return RequestLimits {
+            maxrequestline: DEFAULTMAXREQUESTLINE,
+            maxheadersize: DEFAULTMAXHEADERSIZE,
+            maxheaders: DEFAULTMAXHEADERS,
+            maxbodysize: DEFAULTMAXBODYSIZE,
+        };
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    //  The peer closed the connection before sending a request
+    ConnectionClosed,
+    //  The peer closed the connection part way through a request
+    UnexpectedEof,
+    //  No request was started before the keep-alive timeout
+    IdleTimeout,
+    //  The request started but did not arrive in time
+    RequestTimeout(String),
+    //  The request head is not valid
+    Parse(ParseError),
+    //  The request body could not be framed
+    BadRequest(String),
+    //  The request uses a framing the server does not implement
+    NotImplemented(String),
+    //  The request line is longer than allowed
+    UriTooLong(String),
+    //  The header section is larger or has more fields than allowed
+    HeadersTooLarge(String),
+    //  The body is larger than allowed
+    PayloadTooLarge(String),
+    Io(io::Error),
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ConnectionClosed => write!(f, "Connection closed"),
+            Self::UnexpectedEof => write!(f, "Connection closed before the request was complete"),
+            Self::IdleTimeout => write!(f, "Connection idle for too long"),
+            Self::RequestTimeout(e) => write!(f, "Request timeout: {}", e),
+            Self::Parse(e) => write!(f, "Invalid request: {}", e),
+            Self::BadRequest(e) => write!(f, "Bad request: {}", e),
+            Self::NotImplemented(e) => write!(f, "Not implemented: {}", e),
+            Self::UriTooLong(e) => write!(f, "Request line too long: {}", e),
+            Self::HeadersTooLarge(e) => write!(f, "Headers too large: {}", e),
+            Self::PayloadTooLarge(e) => write!(f, "Payload too large: {}", e),
+            Self::Io(e) => write!(f, "Io error: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+//  Reads complete HTTP/1.1 requests from a stream
+//  Bytes are accumulated until the head terminator is seen and then until the
+//  whole body has arrived, so requests split across many reads are handled
+//  How long to wait for the stream is left to the caller, which fills the reader and takes requests as they complete
+pub struct HttpReader<R: Read> {
+    inner: R,
+    requests: RequestBuffer,
+}
+
+impl<R: Read> HttpReader<R> {
+    pub fn new(inner: R) -> HttpReader<R> {
+        HttpReader { inner, requests: RequestBuffer::new() }
+    }
+
+    pub fn limits(mut self, limits: RequestLimits) -> HttpReader<R> {
+        self.requests = self.requests.limits(limits);
+        return self;
+    }
+
+    //  Parses a request from the bytes already received, if they hold a complete one
+    pub fn take_request(&mut self) -> Result<Option<HttpRequest>, ReadError> {
+        return self.requests.take_request();
+    }
+
+    //  The bytes received and not yet parsed into a request
+    pub fn buffered(&self) -> &[u8] {
+        return self.requests.buffered();
+    }
+
+    //  Reads whatever is available from the stream into the buffer
+    //  Returns the number of bytes read, zero meaning the peer closed the connection
+    pub fn fill(&mut self) -> io::Result<usize> {
+        let mut chunk = [0u8; READCHUNKSIZE];
+
+        loop {
+            match self.inner.read(&mut chunk) {
+                Ok(read) => {
+                    self.requests.extend(&chunk[..read]);
+                    return Ok(read);
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+//  The bytes received on a connection, parsed into requests as they complete
+//  A request whose head has been parsed is kept while its body arrives, so each
+//  read only costs the bytes it added rather than parsing the request again
+pub struct RequestBuffer {
+    buffer: Vec<u8>,
+    limits: RequestLimits,
+    pending: Option<PendingRequest>,
+}
+
+impl Default for RequestBuffer {
+    fn default() -> RequestBuffer {
+        return RequestBuffer::new();
+    }
+}
+
+impl RequestBuffer {
+    pub fn new() -> RequestBuffer {
+        return RequestBuffer { buffer: Vec::new(), limits: RequestLimits::default(), pending: None };
+    }
+
+    pub fn limits(mut self, limits: RequestLimits) -> RequestBuffer {
+        self.limits = limits;
+        return self;
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    //  The bytes received and not yet taken as a request, the head of a request still arriving included
+    pub fn buffered(&self) -> &[u8] {
+        return &self.buffer;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.buffer.is_empty();
+    }
+
+    //  Whether the buffer holds all the next request could need, so that taking the request either gives it or refuses it
+    //  Reading on before then only holds bytes that might never be wanted
+    pub fn is_full(&self) -> bool {
+        let needed = match &self.pending {
+            Some(pending) => pending.bodystart.saturating_add(pending.get_max_body_length()),
+            None => self.limits.maxrequestline + CRLF.len() + self.limits.maxheadersize + HEADTERMINATOR.len(),
+        };
+
+        return self.buffer.len() >= needed;
+    }
+
+    //  Takes the request at the start of the buffer, if all of it has arrived
+    //  A request over the limits is refused as soon as enough of it has arrived to tell
+    pub fn take_request(&mut self) -> Result<Option<HttpRequest>, ReadError> {
+        let mut pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => match parse_head(&self.buffer, &self.limits)? {
+                Some(pending) => pending,
+                None => return Ok(None),
+            },
+        };
+
+        return match pending.read_body(&self.buffer)? {
+            Some(requestend) => {
+                self.buffer.drain(..requestend);
+                Ok(Some(pending.request))
+            },
+            None => {
+                self.pending = Some(pending);
+                Ok(None)
+            },
+        };
+    }
+}
+
+//  A request whose head has been parsed, waiting for its body
+struct PendingRequest {
+    request: HttpRequest,
+    //  Where the body starts in the buffer
+    bodystart: usize,
+    framing: BodyFraming,
+}
+
+enum BodyFraming {
+    Length(usize),
+    Chunked(ChunkedDecoder),
+}
+
+impl PendingRequest {
+    //  The most bytes the body can take up in the buffer before it is either complete or refused
+    fn get_max_body_length(&self) -> usize {
+        return match &self.framing {
+            BodyFraming::Length(length) => *length,
+            BodyFraming::Chunked(decoder) => decoder.get_max_encoded_length(),
+        };
+    }
+
+    //  Fills in the body once it has all arrived
+    //  Returns where the request ends in the buffer, or None when more bytes are needed
+    fn read_body(&mut self, buffer: &[u8]) -> Result<Option<usize>, ReadError> {
+        match &mut self.framing {
+            BodyFraming::Length(length) => {
+                let bodyend = self.bodystart + *length;
+                if buffer.len() < bodyend { return Ok(None); }
+
+                self.request.body = buffer[self.bodystart..bodyend].to_vec();
+                return Ok(Some(bodyend));
+            },
+            BodyFraming::Chunked(decoder) => {
+                let chunkedbody = match decoder.decode(&buffer[self.bodystart..])? {
+                    Some(chunkedbody) => chunkedbody,
+                    None => return Ok(None),
+                };
+
+                self.request.body = chunkedbody.body;
+                self.request.trailers = chunkedbody.trailers;
+                return Ok(Some(self.bodystart + chunkedbody.consumed));
+            },
+        }
+    }
+}
+
+//  Parses the head at the start of the buffer once all of it has arrived, and works out how its body is framed
+fn parse_head(buffer: &[u8], limits: &RequestLimits) -> Result<Option<PendingRequest>, ReadError> {
+    //  Empty lines before a request are ignored, some clients send one after the body of a POST
+    let skipped = get_leading_empty_lines(buffer);
+    let buffer = &buffer[skipped..];
+
+    let headend = find_head_end(buffer);
+    let received = &buffer[..headend.unwrap_or(buffer.len())];
+    check_head_limits(received, skipped, limits)?;
+
+    //  A head with lines ending in a bare LF or CR would never be seen to end, so it is refused as soon as one arrives
+    //  A CR at the very end may still be followed by its LF
+    check_line_endings(received.strip_suffix(b"\r").unwrap_or(received)).map_err(ReadError::Parse)?;
+
+    let headend = match headend {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+
+    let request = parse_request_head(&buffer[..headend]).map_err(ReadError::Parse)?;
+    let bodystart = skipped + headend + HEADTERMINATOR.len();
+
+    if request.headers.contains_key("transfer-encoding") {
+        check_transfer_encoding(&request)?;
+        return Ok(Some(PendingRequest { request, bodystart, framing: BodyFraming::Chunked(ChunkedDecoder::new(limits.maxbodysize)) }));
+    }
+
+    let contentlength = get_content_length(&request)?;
+    if contentlength > limits.maxbodysize {
+        return Err(ReadError::PayloadTooLarge(format!("Content-Length {} over {} bytes", contentlength, limits.maxbodysize)));
+    }
+
+    return Ok(Some(PendingRequest { request, bodystart, framing: BodyFraming::Length(contentlength) }));
+}
+
+//  The length of the request head including the blank line ending it, once all of it has been received
+pub fn get_head_length(buffer: &[u8]) -> Option<usize> {
+    let skipped = get_leading_empty_lines(buffer);
+    return find_head_end(&buffer[skipped..]).map(|headend| skipped + headend + HEADTERMINATOR.len());
+}
+
+//  Read timeouts surface as WouldBlock on unix and TimedOut on windows
+pub fn is_timeout(error: &io::Error) -> bool {
+    return matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut);
+}
+
+//  Checks the request line and header section received so far against the limits
+//  The head may still be incomplete, in which case whatever has arrived is already too much if it is over
+//  Empty lines skipped before the head count towards the request line, so a client sending only those is still stopped
+fn check_head_limits(head: &[u8], skipped: usize, limits: &RequestLimits) -> Result<(), ReadError> {
+    let requestlineend = head.windows(CRLF.len()).position(|window| window == CRLF);
+
+    if skipped + requestlineend.unwrap_or(head.len()) > limits.maxrequestline {
+        return Err(ReadError::UriTooLong(format!("Request line over {} bytes", limits.maxrequestline)));
+    }
+
+    let headers = match requestlineend {
+        Some(requestlineend) => &head[requestlineend + CRLF.len()..],
+        None => return Ok(()),
+    };
+
+    if headers.len() > limits.maxheadersize {
+        return Err(ReadError::HeadersTooLarge(format!("Header section over {} bytes", limits.maxheadersize)));
+    }
+
+    //  Every field but the last is followed by a CRLF, the last one's CRLF is part of the head terminator
+    let fields = headers.windows(CRLF.len()).filter(|window| *window == CRLF).count() + usize::from(!headers.is_empty());
+    if fields > limits.maxheaders {
+        return Err(ReadError::HeadersTooLarge(format!("More than {} header fields", limits.maxheaders)));
+    }
+
+    return Ok(());
+}
+
+//  Counts the bytes of any CRLFs at the start of the buffer
+fn get_leading_empty_lines(buffer: &[u8]) -> usize {
+    return buffer.chunks(CRLF.len()).take_while(|chunk| *chunk == CRLF).count() * CRLF.len();
+}
+
+//  Finds the index of the blank line that ends the request head
+fn find_head_end(buffer: &[u8]) -> Option<usize> {
+    return buffer.windows(HEADTERMINATOR.len()).position(|window| window == HEADTERMINATOR);
+}
+
+//  Checks the request is framed with chunked transfer coding and nothing the server cannot decode
+//  A request with both Content-Length and Transfer-Encoding is ambiguous and is rejected outright
+fn check_transfer_encoding(request: &HttpRequest) -> Result<(), ReadError> {
+    if request.headers.contains_key("content-length") {
+        return Err(ReadError::BadRequest("Both Content-Length and Transfer-Encoding given".to_string()));
+    }
+
+    let transferencoding = request.headers.get("transfer-encoding").map(|value| value.to_lowercase()).unwrap_or_default();
+    let codings = transferencoding.split(',').map(|coding| coding.trim()).collect::<Vec<&str>>();
+
+    if codings.last() != Some(&"chunked") {
+        return Err(ReadError::BadRequest(format!("Chunked is not the final transfer coding in {}", transferencoding)));
+    }
+
+    if codings.len() > 1 {
+        return Err(ReadError::NotImplemented(format!("Unsupported transfer coding {}", transferencoding)));
+    }
+
+    return Ok(());
+}
+
+//  Gets the Content-Length of the request, a missing header means there is no body
+//  Only digits are accepted, a sign or anything else another server might read differently is refused
+fn get_content_length(request: &HttpRequest) -> Result<usize, ReadError> {
+    let value = match request.headers.get("content-length") {
+        Some(value) => value,
+        None => return Ok(0),
+    };
+
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(ReadError::BadRequest(format!("Invalid Content-Length {}", value)));
+    }
+
+    //  Digits that do not fit are more than any limit allows
+    return Ok(value.parse::<usize>().unwrap_or(usize::MAX));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: RequestLimits = RequestLimits { maxrequestline: 64, maxheadersize: 128, maxheaders: 4, maxbodysize: 16 };
+
+    fn take(bytes: &[u8]) -> Result<Option<HttpRequest>, ReadError> {
+        let mut requests = RequestBuffer::new().limits(LIMITS);
+        requests.extend(bytes);
+        return requests.take_request();
+    }
+
+    #[test]
+    fn takes_pipelined_requests_as_they_complete() {
+        let bytes = b"\r\nPOST /a HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\nabcPOST /b HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nde\r\n0\r\n\r\nGET /c HTTP/1.1\r\n";
+        let mut requests = RequestBuffer::new().limits(LIMITS);
+        let mut taken = Vec::new();
+
+        for byte in bytes {
+            requests.extend(&[*byte]);
+            while let Some(request) = requests.take_request().unwrap() {
+                taken.push(request);
+            }
+        }
+
+        assert_eq!(taken.iter().map(|request| request.path.as_str()).collect::<Vec<&str>>(), vec!["/a", "/b"]);
+        assert_eq!(taken[0].body, b"abc");
+        assert_eq!(taken[1].body, b"de");
+        assert_eq!(requests.buffered(), b"GET /c HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn refuses_heads_over_the_limits_before_they_end() {
+        let requestline = format!("GET /{} HTTP/1.1", "a".repeat(64));
+        assert!(matches!(take(requestline.as_bytes()), Err(ReadError::UriTooLong(_))));
+        assert!(matches!(take("\r\n".repeat(33).as_bytes()), Err(ReadError::UriTooLong(_))));
+
+        let header = format!("GET / HTTP/1.1\r\nX-A: {}", "a".repeat(128));
+        assert!(matches!(take(header.as_bytes()), Err(ReadError::HeadersTooLarge(_))));
+        assert!(matches!(take(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\nE"), Err(ReadError::HeadersTooLarge(_))));
+    }
+
+    #[test]
+    fn refuses_bare_line_endings_before_the_head_ends() {
+        assert!(matches!(take(b"GET / HTTP/1.1\nHost: x\n"), Err(ReadError::Parse(ParseError::BareLf))));
+        assert!(matches!(take(b"GET / HTTP/1.1\r"), Ok(None)));
+    }
+
+    #[test]
+    fn frames_bodies_by_content_length() {
+        assert!(matches!(take(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: +3\r\n\r\nabc"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(take(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 17\r\n\r\n"), Err(ReadError::PayloadTooLarge(_))));
+        assert!(matches!(take(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 99999999999999999999999\r\n\r\n"), Err(ReadError::PayloadTooLarge(_))));
+        assert!(matches!(take(b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\n\r\nab"), Ok(None)));
+    }
+
+    #[test]
+    fn checks_transfer_codings() {
+        assert!(matches!(take(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(take(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip\r\n\r\n"), Err(ReadError::BadRequest(_))));
+        assert!(matches!(take(b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"), Err(ReadError::NotImplemented(_))));
+    }
+}
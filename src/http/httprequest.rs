@@ -1,52 +1,44 @@
 use std::{collections::HashMap, str::FromStr};
-use crate::HttpMethod;
+use uuid::Uuid;
+use crate::http::{percent_decode, requestparser::parse_request_head, HttpMethod, ParseError};
 
+#[derive(Clone)]
 pub struct HttpRequest {
+    //  The connection the request arrived on, used to tie log lines together
+    pub sessionid: Uuid,
     pub method: HttpMethod,
     pub path: String,
+    //  Everything after the ? in the request target, empty when there is none
+    pub query: String,
     pub version: String,
     pub headers: HashMap<String, String>,
-    pub body: String,
+    pub body: Vec<u8>,
+    //  Fields sent after a chunked body
+    pub trailers: HashMap<String, String>,
 }
 
-impl FromStr for HttpRequest {
-    type Err = String;
-
-    fn from_str(s: &str) -> Result<Self, String> {
-        let mut lines = s.lines();
-        if s.is_empty() {
-            return Err("Empty request".to_string());
-        }
-        let first_line = lines.next().unwrap();
-        let (method, path, version) = {
-            let mut parts = first_line.split_whitespace();
-            (
-                parts.next().unwrap().to_string().parse::<HttpMethod>().unwrap(),
-                parts.next().unwrap().to_string(),
-                parts.next().unwrap().to_string(),
-            )
-        };
+impl HttpRequest {
+    //  The request target as it was sent, path and query together
+    pub fn get_target(&self) -> String {
+        if self.query.is_empty() { return self.path.clone(); }
+        return format!("{}?{}", self.path, self.query);
+    }
 
-        let mut headers: HashMap<String, String> = HashMap::new();
-        let mut body = String::new();
+    //  Gets the decoded value of the first query parameter with the name
+    pub fn get_query_parameter(&self, name: &str) -> Option<String> {
+        return self.query.split('&')
+            .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+            .find(|(key, _)| *key == name)
+            .and_then(|(_, value)| percent_decode(&value.replace('+', " ")))
+            .map(|value| String::from_utf8_lossy(&value).to_string());
+    }
+}
 
-        for line in lines {
-            if let Some((header, value)) = line.split_once(':') {
-                headers.insert(
-                    header.trim().to_lowercase().to_string(),
-                    value.trim().to_string(),
-                );
-            } else if matches!(method, HttpMethod::POST) && !line.is_empty() {
-                body.push_str(line);
-            }
-        }
+impl FromStr for HttpRequest {
+    type Err = ParseError;
 
-        Ok(Self {
-            method,
-            path,
-            version,
-            headers,
-            body,
-        })
+    //  Parses a request head, see parse_request_head
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        return parse_request_head(s.as_bytes());
     }
-}
\ No newline at end of file
+}
@@ -1,11 +1,10 @@
 use std::collections::HashMap;
 
-use crate::HttpStatusCode;
-use crate::http::HttpVersion;
+use crate::http::{HttpBody, HttpStatusCode, HttpVersion};
 
 pub struct HttpResponse {
     pub head: Parts,
-    pub body: String,
+    pub body: HttpBody,
 }
 
 impl HttpResponse {
@@ -13,15 +12,32 @@ impl HttpResponse {
     pub fn new() -> HttpResponse {
         HttpResponse {
             head: Parts::new(),
-            body: String::new(),
+            body: HttpBody::Empty,
         }
     }
 }
 
+impl Default for HttpResponse {
+    fn default() -> HttpResponse {
+        return HttpResponse::new();
+    }
+}
+
 pub struct Parts {
     pub status: HttpStatusCode,
     pub version: HttpVersion,
     pub headers: HashMap<String, String>,
+    //  Automatic headers (Date, Server, Content-Length, Transfer-Encoding) the server must not add
+    pub suppressedheaders: Vec<String>,
+    //  The whole resource a 304 or 206 stands in for, so later layers can treat it as they would its 200
+    pub representation: Option<Representation>,
+}
+
+//  The content type and length of a resource's full 200 response
+#[derive(Clone)]
+pub struct Representation {
+    pub contenttype: String,
+    pub length: u64,
 }
 
 impl Parts {
@@ -30,6 +46,29 @@ impl Parts {
             status: HttpStatusCode::Ok,
             version: HttpVersion::Http11,
             headers: HashMap::new(),
+            suppressedheaders: Vec::new(),
+            representation: None,
         }
     }
+
+    //  Gets a header ignoring the case of its name
+    pub fn get_header(&self, name: &str) -> Option<&String> {
+        return self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value);
+    }
+
+    //  Removes a header ignoring the case of its name
+    pub fn remove_header(&mut self, name: &str) -> Option<String> {
+        let key = self.headers.keys().find(|key| key.eq_ignore_ascii_case(name))?.clone();
+        return self.headers.remove(&key);
+    }
+
+    //  Stops the server adding the named automatic header to this response
+    //  Suppressing the framing headers means the body is ended by closing the connection
+    pub fn suppress_header(&mut self, name: &str) {
+        self.suppressedheaders.push(name.to_string());
+    }
+
+    pub fn is_suppressed(&self, name: &str) -> bool {
+        return self.suppressedheaders.iter().any(|key| key.eq_ignore_ascii_case(name));
+    }
 }
\ No newline at end of file
@@ -6,6 +6,7 @@ pub enum HttpStatusCode {
     Created = 201,
     Accepted = 202,
     NoContent = 204,
+    PartialContent = 206,
     MovedPermanently = 301,
     Found = 302,
     NotModified = 304,
@@ -21,6 +22,7 @@ pub enum HttpStatusCode {
     Conflict = 409,
     Gone = 410,
     LengthRequired = 411,
+    PreconditionFailed = 412,
     PayloadTooLarge = 413,
     UriTooLong = 414,
     UnsupportedMediaType = 415,
@@ -57,6 +59,7 @@ impl Display for HttpStatusCode {
             Self::Created => write!(f, "201 Created"),
             Self::Accepted => write!(f, "202 Accepted"),
             Self::NoContent => write!(f, "204 No Content"),
+            Self::PartialContent => write!(f, "206 Partial Content"),
             Self::MovedPermanently => write!(f, "301 Moved Permanently"),
             Self::Found => write!(f, "302 Found"),
             Self::NotModified => write!(f, "304 Not Modified"),
@@ -72,6 +75,7 @@ impl Display for HttpStatusCode {
             Self::Conflict => write!(f, "409 Conflict"),
             Self::Gone => write!(f, "410 Gone"),
             Self::LengthRequired => write!(f, "411 Length Required"),
+            Self::PreconditionFailed => write!(f, "412 Precondition Failed"),
             Self::PayloadTooLarge => write!(f, "413 Payload Too Large"),
             Self::UriTooLong => write!(f, "414 URI Too Long"),
             Self::UnsupportedMediaType => write!(f, "415 Unsupported Media Type"),
@@ -1,11 +1,20 @@
+pub use httpbody::HttpBody;
 pub use httpmethod::HttpMethod;
+pub use httpreader::{get_head_length, is_timeout, HttpReader, ReadError, RequestBuffer, RequestLimits};
 pub use httprequest::HttpRequest;
-pub use httpresponse::HttpResponse;
+pub use httpresponse::{HttpResponse, Representation};
 pub use httpstatuscode::HttpStatusCode;
 pub use httpversion::HttpVersion;
+pub use requestparser::ParseError;
+pub use percentencoding::{percent_decode, percent_encode_segment};
 
+mod chunkeddecoder;
+mod httpbody;
 mod httpmethod;
+mod httpreader;
 mod httprequest;
 mod httpresponse;
 mod httpstatuscode;
 mod httpversion;
+mod percentencoding;
+mod requestparser;
@@ -0,0 +1,70 @@
+//  Bytes left as they are when encoding a path segment, the RFC 3986 unreserved set
+const UNRESERVED: &[u8] = b"-._~";
+
+//  Decodes %XX escapes, returning None if an escape is malformed
+pub fn percent_decode(value: &str) -> Option<Vec<u8>> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = bytes.get(index + 1..index + 3)?;
+            //  from_str_radix alone would also take a sign, as in %+1
+            if !hex.iter().all(|byte| byte.is_ascii_hexdigit()) { return None; }
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+
+    return Some(decoded);
+}
+
+//  Encodes everything but unreserved characters so the value can be used as one path segment
+pub fn percent_encode_segment(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || UNRESERVED.contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    return encoded;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc%2f"), Some(b"a b/c/".to_vec()));
+        assert_eq!(percent_decode("%00%FF"), Some(vec![0, 255]));
+        assert_eq!(percent_decode("plain+text"), Some(b"plain+text".to_vec()));
+        assert_eq!(percent_decode(""), Some(vec![]));
+    }
+
+    #[test]
+    fn rejects_malformed_escapes() {
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("a%4"), None);
+        assert_eq!(percent_decode("%G1"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%\u{e9}"), None);
+    }
+
+    #[test]
+    fn encodes_segments_that_decode_back() {
+        assert_eq!(percent_encode_segment("a b/c?d%e-f_g.h~"), "a%20b%2Fc%3Fd%25e-f_g.h~");
+
+        let value = "caf\u{e9} 100%/x";
+        assert_eq!(percent_decode(&percent_encode_segment(value)), Some(value.as_bytes().to_vec()));
+    }
+}
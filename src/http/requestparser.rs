@@ -0,0 +1,400 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+};
+
+use uuid::Uuid;
+
+use crate::http::{HttpMethod, HttpRequest, HttpStatusCode};
+
+const CRLF: &[u8] = b"\r\n";
+const VERSIONPREFIX: &[u8] = b"HTTP/";
+
+//  Why a request head could not be parsed
+//  Each maps onto the status the client is answered with, see get_status_code
+#[derive(Debug)]
+pub enum ParseError {
+    EmptyRequest,
+    //  A CR not followed by an LF
+    BareCr,
+    //  An LF not preceded by a CR
+    BareLf,
+    //  The request line is not method, target and version separated by single spaces
+    MalformedRequestLine(String),
+    //  The method is not a token
+    InvalidMethod(String),
+    //  The method is a token the server does not implement
+    UnsupportedMethod(String),
+    InvalidTarget(String),
+    //  The version is not HTTP/ followed by a digit, a dot and a digit
+    InvalidVersion(String),
+    //  A well formed version with a major version other than 1
+    UnsupportedVersion(String),
+    //  A header line with no colon
+    MalformedHeader(String),
+    //  The field name is not a token, which includes whitespace before the colon
+    InvalidHeaderName(String),
+    //  The field value holds control characters
+    InvalidHeaderValue(String),
+    //  A header line continued on the next line by starting it with whitespace
+    ObsFold,
+    MissingHost,
+    DuplicateHost,
+    //  Several Content-Length fields that do not agree
+    ConflictingContentLength,
+}
+
+impl ParseError {
+    //  The status the client is answered with
+    //  Methods and versions the server does not support are not the client's fault, everything else is
+    pub fn get_status_code(&self) -> HttpStatusCode {
+        return match self {
+            Self::UnsupportedMethod(_) => HttpStatusCode::NotImplemented,
+            Self::UnsupportedVersion(_) => HttpStatusCode::HTTPVersionNotSupported,
+            _ => HttpStatusCode::BadRequest,
+        };
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmptyRequest => write!(f, "Empty request"),
+            Self::BareCr => write!(f, "CR not followed by LF"),
+            Self::BareLf => write!(f, "LF not preceded by CR"),
+            Self::MalformedRequestLine(line) => write!(f, "Malformed request line {}", line),
+            Self::InvalidMethod(method) => write!(f, "Invalid method {}", method),
+            Self::UnsupportedMethod(method) => write!(f, "Unsupported method {}", method),
+            Self::InvalidTarget(target) => write!(f, "Invalid request target {}", target),
+            Self::InvalidVersion(version) => write!(f, "Invalid version {}", version),
+            Self::UnsupportedVersion(version) => write!(f, "Unsupported version {}", version),
+            Self::MalformedHeader(line) => write!(f, "Malformed header line {}", line),
+            Self::InvalidHeaderName(name) => write!(f, "Invalid header name {}", name),
+            Self::InvalidHeaderValue(name) => write!(f, "Invalid value for header {}", name),
+            Self::ObsFold => write!(f, "Header line folded onto the next line"),
+            Self::MissingHost => write!(f, "Missing Host header"),
+            Self::DuplicateHost => write!(f, "More than one Host header"),
+            Self::ConflictingContentLength => write!(f, "Conflicting Content-Length headers"),
+        }
+    }
+}
+
+//  Parses a request head, the request line and header lines without the blank line ending them
+//  Follows RFC 9112 strictly, anything a lenient parser would have to guess at is refused
+pub fn parse_request_head(head: &[u8]) -> Result<HttpRequest, ParseError> {
+    if head.is_empty() { return Err(ParseError::EmptyRequest); }
+    check_line_endings(head)?;
+
+    let mut lines = split_lines(head);
+    let requestline = lines.next().unwrap_or_default();
+    let (method, target, version) = parse_request_line(requestline)?;
+    let (path, query) = parse_target(&method, &target)?;
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for line in lines {
+        let (name, value) = parse_header_line(line)?;
+        add_header(&mut headers, name, value)?;
+    }
+
+    //  Every HTTP/1.1 request names the host it is for, HTTP/1.0 ones may leave it out
+    if version != "HTTP/1.0" && !headers.contains_key("host") { return Err(ParseError::MissingHost); }
+
+    //  The body is not part of the head, the reader fills it in once it has arrived
+    return Ok(HttpRequest {
+        sessionid: Uuid::nil(),
+        method,
+        path,
+        query,
+        version,
+        headers,
+        body: Vec::new(),
+        trailers: HashMap::new(),
+    });
+}
+
+//  Lines end in CRLF, a CR or LF on its own is refused as different parsers disagree on where it splits
+pub fn check_line_endings(head: &[u8]) -> Result<(), ParseError> {
+    for (index, byte) in head.iter().enumerate() {
+        match byte {
+            b'\r' if head.get(index + 1) != Some(&b'\n') => return Err(ParseError::BareCr),
+            b'\n' if index == 0 || head[index - 1] != b'\r' => return Err(ParseError::BareLf),
+            _ => {},
+        }
+    }
+
+    return Ok(());
+}
+
+fn split_lines(head: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = Some(head);
+
+    return std::iter::from_fn(move || {
+        let current = rest?;
+        match current.windows(CRLF.len()).position(|window| window == CRLF) {
+            Some(end) => {
+                rest = Some(&current[end + CRLF.len()..]);
+                Some(&current[..end])
+            },
+            None => {
+                rest = None;
+                Some(current)
+            },
+        }
+    });
+}
+
+//  request-line = method SP request-target SP HTTP-version
+fn parse_request_line(line: &[u8]) -> Result<(HttpMethod, String, String), ParseError> {
+    let text = String::from_utf8_lossy(line).to_string();
+    let parts = line.split(|byte| *byte == b' ').collect::<Vec<&[u8]>>();
+
+    if parts.len() != 3 || parts.iter().any(|part| part.is_empty()) {
+        return Err(ParseError::MalformedRequestLine(text));
+    }
+
+    let method = String::from_utf8_lossy(parts[0]).to_string();
+    if !is_token(parts[0]) { return Err(ParseError::InvalidMethod(method)); }
+
+    //  CONNECT asks the server to act as a tunnel, which it does not
+    let method = match method.parse::<HttpMethod>() {
+        Ok(HttpMethod::CONNECT) | Err(_) => return Err(ParseError::UnsupportedMethod(method)),
+        Ok(method) => method,
+    };
+
+    let target = String::from_utf8_lossy(parts[1]).to_string();
+    if !parts[1].iter().all(|byte| byte.is_ascii_graphic()) || parts[1].contains(&b'#') {
+        return Err(ParseError::InvalidTarget(target));
+    }
+
+    let version = parse_version(parts[2])?;
+
+    return Ok((method, target, version));
+}
+
+//  HTTP-version = "HTTP/" DIGIT "." DIGIT
+fn parse_version(version: &[u8]) -> Result<String, ParseError> {
+    let text = String::from_utf8_lossy(version).to_string();
+
+    match version.strip_prefix(VERSIONPREFIX) {
+        Some([major, b'.', minor]) if major.is_ascii_digit() && minor.is_ascii_digit() => {
+            if *major != b'1' { return Err(ParseError::UnsupportedVersion(text)); }
+            return Ok(text);
+        },
+        _ => return Err(ParseError::InvalidVersion(text)),
+    }
+}
+
+//  Splits the request target into its path and query
+//  Targets come in origin form, /path?query, absolute form, http://host/path?query, or as * for OPTIONS
+fn parse_target(method: &HttpMethod, target: &str) -> Result<(String, String), ParseError> {
+    let pathandquery = if target.starts_with('/') {
+        target
+    } else if target == "*" && matches!(method, HttpMethod::OPTIONS) {
+        return Ok((target.to_string(), String::new()));
+    } else {
+        match get_absolute_path(target) {
+            Some(pathandquery) => pathandquery,
+            None => return Err(ParseError::InvalidTarget(target.to_string())),
+        }
+    };
+
+    return Ok(match pathandquery.split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (pathandquery.to_string(), String::new()),
+    });
+}
+
+//  Gets the path and query of an absolute form target, a target with no path asks for /
+fn get_absolute_path(target: &str) -> Option<&str> {
+    let (scheme, rest) = target.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") { return None; }
+
+    let pathstart = rest.find(['/', '?']).unwrap_or(rest.len());
+    if pathstart == 0 { return None; }
+
+    return match &rest[pathstart..] {
+        "" => Some("/"),
+        pathandquery if pathandquery.starts_with('?') => None,
+        pathandquery => Some(pathandquery),
+    };
+}
+
+//  field-line = field-name ":" OWS field-value OWS
+fn parse_header_line(line: &[u8]) -> Result<(String, String), ParseError> {
+    if line.first().is_some_and(|byte| *byte == b' ' || *byte == b'\t') { return Err(ParseError::ObsFold); }
+
+    let colon = match line.iter().position(|byte| *byte == b':') {
+        Some(colon) => colon,
+        None => return Err(ParseError::MalformedHeader(String::from_utf8_lossy(line).to_string())),
+    };
+
+    let name = &line[..colon];
+    if !is_token(name) { return Err(ParseError::InvalidHeaderName(String::from_utf8_lossy(name).to_string())); }
+    let name = String::from_utf8_lossy(name).to_lowercase();
+
+    let value = trim_whitespace(&line[colon + 1..]);
+    if value.iter().any(|byte| byte.is_ascii_control() && *byte != b'\t') {
+        return Err(ParseError::InvalidHeaderValue(name));
+    }
+
+    //  Values are meant to be ASCII, anything else that is not UTF-8 is taken to be Latin-1 as older clients send
+    let value = match std::str::from_utf8(value) {
+        Ok(value) => value.to_string(),
+        Err(_) => value.iter().map(|byte| *byte as char).collect(),
+    };
+
+    return Ok((name, value));
+}
+
+//  Fields sent more than once are combined into one comma separated value, except those that cannot be
+fn add_header(headers: &mut HashMap<String, String>, name: String, value: String) -> Result<(), ParseError> {
+    let existing = match headers.get_mut(&name) {
+        Some(existing) => existing,
+        None => {
+            headers.insert(name, value);
+            return Ok(());
+        },
+    };
+
+    match name.as_str() {
+        "host" => return Err(ParseError::DuplicateHost),
+        "content-length" if *existing != value => return Err(ParseError::ConflictingContentLength),
+        "content-length" => {},
+        "cookie" => existing.push_str(&format!("; {}", value)),
+        _ => existing.push_str(&format!(", {}", value)),
+    }
+
+    return Ok(());
+}
+
+fn trim_whitespace(value: &[u8]) -> &[u8] {
+    let start = value.iter().position(|byte| *byte != b' ' && *byte != b'\t').unwrap_or(value.len());
+    let end = value.iter().rposition(|byte| *byte != b' ' && *byte != b'\t').map(|end| end + 1).unwrap_or(start);
+
+    return &value[start..end];
+}
+
+//  token = 1*tchar
+fn is_token(value: &[u8]) -> bool {
+    return !value.is_empty() && value.iter().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(byte));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(head: &str) -> Result<HttpRequest, ParseError> {
+        return parse_request_head(head.as_bytes());
+    }
+
+    #[test]
+    fn parses_origin_form_request() {
+        let request = parse("GET /files/a.txt?x=1&y=2 HTTP/1.1\r\nHost: example.com\r\nAccept: */*").unwrap();
+
+        assert!(matches!(request.method, HttpMethod::GET));
+        assert_eq!(request.path, "/files/a.txt");
+        assert_eq!(request.query, "x=1&y=2");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.headers.get("host").map(String::as_str), Some("example.com"));
+        assert_eq!(request.headers.get("accept").map(String::as_str), Some("*/*"));
+    }
+
+    #[test]
+    fn parses_absolute_form_and_asterisk_targets() {
+        let request = parse("GET http://example.com/a?b HTTP/1.1\r\nHost: example.com").unwrap();
+        assert_eq!(request.path, "/a");
+        assert_eq!(request.query, "b");
+
+        let request = parse("GET https://example.com HTTP/1.1\r\nHost: example.com").unwrap();
+        assert_eq!(request.path, "/");
+
+        let request = parse("OPTIONS * HTTP/1.1\r\nHost: example.com").unwrap();
+        assert_eq!(request.path, "*");
+
+        assert!(matches!(parse("GET * HTTP/1.1\r\nHost: x"), Err(ParseError::InvalidTarget(_))));
+        assert!(matches!(parse("GET ftp://example.com/ HTTP/1.1\r\nHost: x"), Err(ParseError::InvalidTarget(_))));
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        assert!(matches!(parse(""), Err(ParseError::EmptyRequest)));
+        assert!(matches!(parse("GET  / HTTP/1.1\r\nHost: x"), Err(ParseError::MalformedRequestLine(_))));
+        assert!(matches!(parse("GET / HTTP/1.1 \r\nHost: x"), Err(ParseError::MalformedRequestLine(_))));
+        assert!(matches!(parse("GET /\r\nHost: x"), Err(ParseError::MalformedRequestLine(_))));
+        assert!(matches!(parse("GET /a#b HTTP/1.1\r\nHost: x"), Err(ParseError::InvalidTarget(_))));
+        assert!(matches!(parse("GET /a\x7fb HTTP/1.1\r\nHost: x"), Err(ParseError::InvalidTarget(_))));
+    }
+
+    #[test]
+    fn maps_methods_to_statuses() {
+        let error = parse("BL@H / HTTP/1.1\r\nHost: x").err().unwrap();
+        assert!(matches!(error, ParseError::InvalidMethod(_)));
+        assert!(matches!(error.get_status_code(), HttpStatusCode::BadRequest));
+
+        let error = parse("FOO / HTTP/1.1\r\nHost: x").err().unwrap();
+        assert!(matches!(error, ParseError::UnsupportedMethod(_)));
+        assert!(matches!(error.get_status_code(), HttpStatusCode::NotImplemented));
+
+        assert!(matches!(parse("CONNECT example.com:443 HTTP/1.1\r\nHost: x"), Err(ParseError::UnsupportedMethod(_))));
+        assert!(matches!(parse("get / HTTP/1.1\r\nHost: x"), Err(ParseError::UnsupportedMethod(_))));
+    }
+
+    #[test]
+    fn maps_versions_to_statuses() {
+        let error = parse("GET / HTTP/2.0\r\nHost: x").err().unwrap();
+        assert!(matches!(error, ParseError::UnsupportedVersion(_)));
+        assert!(matches!(error.get_status_code(), HttpStatusCode::HTTPVersionNotSupported));
+
+        assert!(matches!(parse("GET / HTTP/1.x1\r\nHost: x"), Err(ParseError::InvalidVersion(_))));
+        assert!(matches!(parse("GET / HTTP/1.10\r\nHost: x"), Err(ParseError::InvalidVersion(_))));
+        assert!(matches!(parse("GET / http/1.1\r\nHost: x"), Err(ParseError::InvalidVersion(_))));
+    }
+
+    #[test]
+    fn rejects_bare_line_endings() {
+        assert!(matches!(parse("GET / HTTP/1.1\nHost: x"), Err(ParseError::BareLf)));
+        assert!(matches!(parse("GET / HTTP/1.1\rHost: x"), Err(ParseError::BareCr)));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost: x\r"), Err(ParseError::BareCr)));
+    }
+
+    #[test]
+    fn rejects_malformed_header_lines() {
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost: x\r\nX-A: a\r\n b"), Err(ParseError::ObsFold)));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost: x\r\nX-A"), Err(ParseError::MalformedHeader(_))));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost : x"), Err(ParseError::InvalidHeaderName(_))));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost: x\r\n: y"), Err(ParseError::InvalidHeaderName(_))));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost: x\r\nX-A: a\x00b"), Err(ParseError::InvalidHeaderValue(_))));
+    }
+
+    #[test]
+    fn trims_values_and_decodes_latin1() {
+        let request = parse("GET / HTTP/1.1\r\nHost: x\r\nX-A: \t a b \t").unwrap();
+        assert_eq!(request.headers.get("x-a").map(String::as_str), Some("a b"));
+
+        let request = parse_request_head(b"GET / HTTP/1.1\r\nHost: x\r\nX-A: caf\xe9").unwrap();
+        assert_eq!(request.headers.get("x-a").map(String::as_str), Some("caf\u{e9}"));
+    }
+
+    #[test]
+    fn requires_one_host_on_http11() {
+        assert!(matches!(parse("GET / HTTP/1.1"), Err(ParseError::MissingHost)));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost: a\r\nHost: b"), Err(ParseError::DuplicateHost)));
+        assert!(parse("GET / HTTP/1.0").is_ok());
+    }
+
+    #[test]
+    fn combines_repeated_fields() {
+        let request = parse("GET / HTTP/1.1\r\nHost: x\r\nAccept: a\r\nAccept: b\r\nCookie: c=1\r\nCookie: d=2").unwrap();
+
+        assert_eq!(request.headers.get("accept").map(String::as_str), Some("a, b"));
+        assert_eq!(request.headers.get("cookie").map(String::as_str), Some("c=1; d=2"));
+    }
+
+    #[test]
+    fn rejects_conflicting_content_lengths() {
+        assert!(matches!(parse("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\nContent-Length: 2"), Err(ParseError::ConflictingContentLength)));
+
+        let request = parse("POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 3\r\nContent-Length: 3").unwrap();
+        assert_eq!(request.headers.get("content-length").map(String::as_str), Some("3"));
+    }
+}
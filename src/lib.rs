@@ -0,0 +1,33 @@
+#![allow(clippy::needless_return)]
+
+//  A small HTTP/1.1 server for static files and simple APIs
+//  Embed it with Server::builder(), route requests with a Router and wrap handlers in middleware
+
+pub mod compression;
+mod config;
+mod connection;
+mod directorylisting;
+mod eventloop;
+pub mod handler;
+pub mod http;
+pub mod middleware;
+pub mod mimetypes;
+pub mod pathresolver;
+mod preconditions;
+mod ranges;
+pub mod responses;
+pub mod router;
+pub mod server;
+pub mod shutdown;
+pub mod staticfiles;
+mod threads;
+mod timeouts;
+
+pub use eventloop::IoMode;
+pub use handler::Handler;
+pub use middleware::{Middleware, Next, Pipeline};
+pub use router::{PathParams, Router};
+pub use server::{Server, ServerBuilder, ServerHandle};
+pub use shutdown::ShutdownHandle;
+pub use staticfiles::StaticFiles;
+pub use threads::{OverloadPolicy, PoolMonitor, PoolStats};
@@ -0,0 +1,205 @@
+use std::time::Instant;
+
+use log::*;
+
+use crate::{
+    compression::{self, CompressionConfig},
+    handler::Handler,
+    http::{HttpRequest, HttpResponse, HttpStatusCode},
+};
+
+//  Runs around a handler, able to change the request before it and the response after it
+//  The request is borrowed through the chain, to change it pass a modified copy to next
+//  Returning a response without calling next stops the request reaching the handler at all
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &HttpRequest, next: Next) -> HttpResponse;
+}
+
+//  The rest of the chain after the current middleware
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl Next<'_> {
+    //  Passes the request on to the next middleware, or the handler once there are none left
+    pub fn run(self, request: &HttpRequest) -> HttpResponse {
+        return match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(request, Next { middleware: rest, handler: self.handler }),
+            None => self.handler.call(request),
+        };
+    }
+}
+
+//  A handler wrapped in middleware, which runs in the order it was added
+pub struct Pipeline {
+    middleware: Vec<Box<dyn Middleware>>,
+    handler: Box<dyn Handler>,
+}
+
+impl Pipeline {
+    pub fn new(handler: impl Handler + 'static) -> Pipeline {
+        return Pipeline { middleware: Vec::new(), handler: Box::new(handler) };
+    }
+
+    pub(crate) fn from_parts(middleware: Vec<Box<dyn Middleware>>, handler: Box<dyn Handler>) -> Pipeline {
+        return Pipeline { middleware, handler };
+    }
+
+    //  Adds middleware inside any added before it
+    pub fn with(mut self, middleware: impl Middleware + 'static) -> Pipeline {
+        self.middleware.push(Box::new(middleware));
+        return self;
+    }
+
+    pub fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        return Next { middleware: &self.middleware, handler: self.handler.as_ref() }.run(request);
+    }
+}
+
+impl Handler for Pipeline {
+    fn call(&self, request: &HttpRequest) -> HttpResponse {
+        return self.handle(request);
+    }
+}
+
+//  Logs each request line and the status it was answered with
+pub struct LoggingMiddleware;
+
+impl Middleware for LoggingMiddleware {
+    fn handle(&self, request: &HttpRequest, next: Next) -> HttpResponse {
+        info!("{},{} {} {}", request.sessionid, &request.method, &request.get_target(), &request.version);
+
+        let started = Instant::now();
+        let response = next.run(request);
+
+        info!("{},Answered {} in {}ms", request.sessionid, response.head.status, started.elapsed().as_millis());
+        return response;
+    }
+}
+
+//  Compresses response bodies with the best coding the client accepts
+pub struct CompressionMiddleware {
+    config: CompressionConfig,
+}
+
+impl CompressionMiddleware {
+    pub fn new(config: CompressionConfig) -> CompressionMiddleware {
+        return CompressionMiddleware { config };
+    }
+}
+
+impl Middleware for CompressionMiddleware {
+    fn handle(&self, request: &HttpRequest, next: Next) -> HttpResponse {
+        let mut response = next.run(request);
+
+        if let Err(e) = compression::compress_response(&self.config, request, &mut response) {
+            error!("{},Could not compress response: {}", request.sessionid, e);
+            response = HttpResponse::new();
+            response.head.status = HttpStatusCode::InternalServerError;
+        }
+
+        return response;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex};
+
+    use super::*;
+    use crate::{http::HttpBody, responses::{create_empty_response, create_response}};
+
+    //  Records its name on the way in and out of the chain
+    struct Recorder {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for Recorder {
+        fn handle(&self, request: &HttpRequest, next: Next) -> HttpResponse {
+            self.calls.lock().unwrap().push(format!("{} in", self.name));
+            let response = next.run(request);
+            self.calls.lock().unwrap().push(format!("{} out", self.name));
+            return response;
+        }
+    }
+
+    //  Answers 401 to requests without an Authorization header
+    struct RequireAuthorization;
+
+    impl Middleware for RequireAuthorization {
+        fn handle(&self, request: &HttpRequest, next: Next) -> HttpResponse {
+            if !request.headers.contains_key("authorization") { return create_empty_response(&request.sessionid, HttpStatusCode::Unauthorized); }
+            return next.run(request);
+        }
+    }
+
+    //  Rewrites the path before the handler sees it
+    struct StripPrefix;
+
+    impl Middleware for StripPrefix {
+        fn handle(&self, request: &HttpRequest, next: Next) -> HttpResponse {
+            let mut request = request.clone();
+            request.path = request.path.trim_start_matches("/api").to_string();
+            return next.run(&request);
+        }
+    }
+
+    fn parse(head: &str) -> HttpRequest {
+        return format!("{}\r\nHost: x", head).parse::<HttpRequest>().unwrap();
+    }
+
+    fn echo_path(request: &HttpRequest) -> HttpResponse {
+        return create_response(&request.sessionid, HttpStatusCode::Ok, "text/plain", request.path.clone());
+    }
+
+    #[test]
+    fn runs_middleware_in_the_order_added() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let pipeline = Pipeline::new(echo_path)
+            .with(Recorder { name: "outer", calls: calls.clone() })
+            .with(Recorder { name: "inner", calls: calls.clone() });
+
+        pipeline.handle(&parse("GET / HTTP/1.1"));
+
+        assert_eq!(*calls.lock().unwrap(), vec!["outer in", "inner in", "inner out", "outer out"]);
+    }
+
+    #[test]
+    fn short_circuits_before_the_handler() {
+        let called = Arc::new(AtomicUsize::new(0));
+        let handlercalled = called.clone();
+        let pipeline = Pipeline::new(move |request: &HttpRequest| {
+            handlercalled.fetch_add(1, Ordering::SeqCst);
+            return echo_path(request);
+        }).with(RequireAuthorization);
+
+        assert!(matches!(pipeline.handle(&parse("GET / HTTP/1.1")).head.status, HttpStatusCode::Unauthorized));
+        assert_eq!(called.load(Ordering::SeqCst), 0);
+        assert!(matches!(pipeline.handle(&parse("GET / HTTP/1.1\r\nAuthorization: Basic eDp5")).head.status, HttpStatusCode::Ok));
+        assert_eq!(called.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn passes_a_changed_request_on() {
+        let pipeline = Pipeline::new(echo_path).with(StripPrefix);
+
+        assert!(matches!(pipeline.handle(&parse("GET /api/items HTTP/1.1")).body, HttpBody::Bytes(ref body) if body == b"/items"));
+    }
+
+    #[test]
+    fn hands_the_handler_the_callers_request() {
+        let seen = Arc::new(AtomicUsize::new(0));
+        let handlerseen = seen.clone();
+        let pipeline = Pipeline::new(move |request: &HttpRequest| {
+            handlerseen.store(request as *const HttpRequest as usize, Ordering::SeqCst);
+            return echo_path(request);
+        }).with(LoggingMiddleware);
+
+        let request = parse("POST / HTTP/1.1");
+        Handler::call(&pipeline, &request);
+
+        assert_eq!(seen.load(Ordering::SeqCst), &request as *const HttpRequest as usize);
+    }
+}
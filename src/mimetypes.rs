@@ -0,0 +1,217 @@
+use std::{collections::HashMap, path::Path};
+
+//  Served when the extension is unknown so browsers do not try to render the file
+const DEFAULTMIMETYPE: &str = "application/octet-stream";
+const DEFAULTCHARSET: &str = "utf-8";
+
+//  Extension to MIME type mappings used when none are configured
+const DEFAULTMIMETYPES: [(&str, &str); 58] = [
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("json", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("xml", "application/xml"),
+    ("rss", "application/rss+xml"),
+    ("atom", "application/atom+xml"),
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("toml", "application/toml"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("eot", "application/vnd.ms-fontobject"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("7z", "application/x-7z-compressed"),
+    ("br", "application/x-brotli"),
+    ("zst", "application/zstd"),
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("flac", "audio/flac"),
+    ("m4a", "audio/mp4"),
+    ("aac", "audio/aac"),
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mov", "video/quicktime"),
+    ("avi", "video/x-msvideo"),
+    ("mkv", "video/x-matroska"),
+    ("vtt", "text/vtt"),
+];
+
+//  Types that are text even though they are not under text/
+const TEXTMIMETYPES: [&str; 9] = [
+    "application/json",
+    "application/ld+json",
+    "application/manifest+json",
+    "application/xml",
+    "application/rss+xml",
+    "application/atom+xml",
+    "application/yaml",
+    "application/toml",
+    "image/svg+xml",
+];
+
+//  Maps file extensions to the Content-Type they are served with
+pub struct MimeTypes {
+    types: HashMap<String, String>,
+}
+
+impl MimeTypes {
+    pub fn new() -> MimeTypes {
+        let types = DEFAULTMIMETYPES.iter()
+            .map(|(extension, mimetype)| (extension.to_string(), mimetype.to_string()))
+            .collect();
+
+        return MimeTypes { types };
+    }
+
+    //  Adds or replaces the type for an extension
+    pub fn insert(&mut self, extension: &str, mimetype: &str) {
+        self.types.insert(extension.trim_start_matches('.').to_lowercase(), mimetype.to_string());
+    }
+
+    //  Loads mappings from a file, replacing any defaults for the same extensions
+    //  Each line is an extension followed by its type, e.g. "md text/markdown; charset=utf-8"
+    //  Blank lines and lines starting with # are ignored
+    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read mime types file {}: {}", path, e))?;
+
+        for line in contents.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            match line.split_once(char::is_whitespace) {
+                Some((extension, mimetype)) => self.insert(extension, mimetype.trim()),
+                None => return Err(format!("Invalid mime type mapping {}", line)),
+            }
+        }
+
+        return Ok(());
+    }
+
+    //  Gets the Content-Type for a file from its extension
+    //  Text types get a charset unless the mapping already names one
+    pub fn get_content_type(&self, path: &Path) -> String {
+        let mimetype = path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| self.types.get(&extension.to_lowercase()))
+            .map(|mimetype| mimetype.as_str())
+            .unwrap_or(DEFAULTMIMETYPE);
+
+        if is_text(mimetype) && !mimetype.contains("charset=") {
+            return format!("{}; charset={}", mimetype, DEFAULTCHARSET);
+        }
+
+        return mimetype.to_string();
+    }
+}
+
+impl Default for MimeTypes {
+    fn default() -> MimeTypes {
+        return MimeTypes::new();
+    }
+}
+
+fn is_text(mimetype: &str) -> bool {
+    let essence = mimetype.split(';').next().unwrap_or_default().trim();
+    return essence.starts_with("text/") || TEXTMIMETYPES.contains(&essence);
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn get_content_type(mimetypes: &MimeTypes, path: &str) -> String {
+        return mimetypes.get_content_type(Path::new(path));
+    }
+
+    //  Writes the mappings to a file of their own and loads them
+    fn load(contents: &str) -> Result<MimeTypes, String> {
+        let path = std::env::temp_dir().join(format!("mimetypes-{}", Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+
+        let mut mimetypes = MimeTypes::new();
+        let result = mimetypes.load_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        return result.map(|_| mimetypes);
+    }
+
+    #[test]
+    fn detects_types_from_extensions() {
+        let mimetypes = MimeTypes::new();
+
+        assert_eq!(get_content_type(&mimetypes, "/www/photo.PNG"), "image/png");
+        assert_eq!(get_content_type(&mimetypes, "/www/archive.tar.gz"), "application/gzip");
+        assert_eq!(get_content_type(&mimetypes, "/www/app.wasm"), "application/wasm");
+    }
+
+    #[test]
+    fn falls_back_to_octet_stream() {
+        let mimetypes = MimeTypes::new();
+
+        assert_eq!(get_content_type(&mimetypes, "/www/data.unknown"), "application/octet-stream");
+        assert_eq!(get_content_type(&mimetypes, "/www/Makefile"), "application/octet-stream");
+        assert_eq!(get_content_type(&mimetypes, "/www/.hidden"), "application/octet-stream");
+    }
+
+    #[test]
+    fn adds_a_charset_to_text_types() {
+        let mimetypes = MimeTypes::new();
+
+        assert_eq!(get_content_type(&mimetypes, "/www/index.html"), "text/html; charset=utf-8");
+        assert_eq!(get_content_type(&mimetypes, "/www/data.json"), "application/json; charset=utf-8");
+        assert_eq!(get_content_type(&mimetypes, "/www/logo.svg"), "image/svg+xml; charset=utf-8");
+    }
+
+    #[test]
+    fn overrides_replace_defaults() {
+        let mut mimetypes = MimeTypes::new();
+        mimetypes.insert(".JS", "application/javascript");
+        mimetypes.insert("txt", "text/plain; charset=iso-8859-1");
+        mimetypes.insert("dat", "application/x-custom");
+
+        assert_eq!(get_content_type(&mimetypes, "/www/app.js"), "application/javascript");
+        assert_eq!(get_content_type(&mimetypes, "/www/notes.txt"), "text/plain; charset=iso-8859-1");
+        assert_eq!(get_content_type(&mimetypes, "/www/file.DAT"), "application/x-custom");
+    }
+
+    #[test]
+    fn loads_mappings_from_a_file() {
+        let mimetypes = load("# overrides\n\nmd\ttext/x-markdown\n  log   text/plain; charset=us-ascii  \n").unwrap();
+
+        assert_eq!(get_content_type(&mimetypes, "/www/README.md"), "text/x-markdown; charset=utf-8");
+        assert_eq!(get_content_type(&mimetypes, "/www/server.log"), "text/plain; charset=us-ascii");
+        assert_eq!(get_content_type(&mimetypes, "/www/style.css"), "text/css; charset=utf-8");
+
+        assert!(load("md\n").is_err());
+        assert!(MimeTypes::new().load_file("/nonexistent/mimetypes").is_err());
+    }
+}
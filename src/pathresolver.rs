@@ -0,0 +1,216 @@
+use std::{
+    fmt::{Display, Formatter},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::http::{percent_decode, percent_encode_segment, HttpStatusCode};
+
+//  How symbolic links under the root are treated
+pub enum SymlinkPolicy {
+    //  Links are followed wherever they point
+    Follow,
+    //  Links are followed as long as their target is inside the root
+    FollowWithinRoot,
+    //  Any link in the path is refused
+    Deny,
+}
+
+impl Default for SymlinkPolicy {
+    fn default() -> SymlinkPolicy {
+        return SymlinkPolicy::FollowWithinRoot;
+    }
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "follow" => Ok(Self::Follow),
+            "withinroot" => Ok(Self::FollowWithinRoot),
+            "deny" => Ok(Self::Deny),
+            _ => Err("Invalid symlink policy".to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PathError {
+    //  The path is malformed, e.g. a bad percent encoding or a NUL byte
+    BadRequest(String),
+    //  The path leaves the root or uses a symlink the policy does not allow
+    Forbidden(String),
+    NotFound,
+}
+
+impl PathError {
+    pub fn get_status_code(&self) -> HttpStatusCode {
+        match self {
+            Self::BadRequest(_) => HttpStatusCode::BadRequest,
+            Self::Forbidden(_) => HttpStatusCode::Forbidden,
+            Self::NotFound => HttpStatusCode::NotFound,
+        }
+    }
+}
+
+impl Display for PathError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadRequest(e) => write!(f, "Bad path: {}", e),
+            Self::Forbidden(e) => write!(f, "Forbidden path: {}", e),
+            Self::NotFound => write!(f, "Path not found"),
+        }
+    }
+}
+
+//  Maps request paths onto files, keeping every resolved path inside the root
+pub struct PathResolver {
+    root: PathBuf,
+    symlinkpolicy: SymlinkPolicy,
+}
+
+impl PathResolver {
+    //  Fails when the root does not exist as it cannot be canonicalised
+    pub fn new(root: &str, symlinkpolicy: SymlinkPolicy) -> Result<PathResolver, String> {
+        let root = Path::new(root).canonicalize().map_err(|e| format!("Invalid root {}: {}", root, e))?;
+
+        return Ok(PathResolver { root, symlinkpolicy });
+    }
+
+    //  Resolves a request path to the canonical path of an existing file or directory under the root
+    //  The path is percent-decoded and its dot segments removed before it touches the file system
+    pub fn resolve(&self, requestpath: &str) -> Result<PathBuf, PathError> {
+        let decoded = decode_path(requestpath)?;
+        let segments = remove_dot_segments(&decoded)?;
+
+        let mut path = self.root.clone();
+        for segment in &segments {
+            path.push(segment);
+
+            if matches!(self.symlinkpolicy, SymlinkPolicy::Deny) {
+                let metadata = path.symlink_metadata().map_err(|_| PathError::NotFound)?;
+                if metadata.file_type().is_symlink() { return Err(PathError::Forbidden(format!("{} is a symlink", path.display()))); }
+            }
+        }
+
+        return self.canonicalize(&path);
+    }
+
+    //  Resolves a file next to an already resolved one, e.g. app.js.gz for app.js
+    pub fn resolve_sibling(&self, path: &Path, extension: &str) -> Result<PathBuf, PathError> {
+        let mut sibling = path.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(extension);
+        let sibling = PathBuf::from(sibling);
+
+        if matches!(self.symlinkpolicy, SymlinkPolicy::Deny) {
+            let metadata = sibling.symlink_metadata().map_err(|_| PathError::NotFound)?;
+            if metadata.file_type().is_symlink() { return Err(PathError::Forbidden(format!("{} is a symlink", sibling.display()))); }
+        }
+
+        return self.canonicalize(&sibling);
+    }
+
+    //  Canonicalises the path and checks the result is somewhere the policy allows
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, PathError> {
+        let canonical = path.canonicalize().map_err(|_| PathError::NotFound)?;
+
+        if !matches!(self.symlinkpolicy, SymlinkPolicy::Follow) && !canonical.starts_with(&self.root) {
+            return Err(PathError::Forbidden(format!("{} is outside the root", canonical.display())));
+        }
+
+        return Ok(canonical);
+    }
+}
+
+//  Gets the request path as it resolves, with dot segments applied and repeated slashes collapsed, re-encoded
+//  Safe to send back in a Location header, as a path starting with // would point browsers at another host
+pub fn normalize_path(requestpath: &str) -> Result<String, PathError> {
+    let segments = remove_dot_segments(&decode_path(requestpath)?)?;
+
+    return Ok(format!("/{}", segments.iter().map(|segment| percent_encode_segment(segment)).collect::<Vec<String>>().join("/")));
+}
+
+//  Decodes %XX escapes in the path
+//  Rejects malformed escapes and anything decoding to a NUL, a backslash or invalid UTF-8
+fn decode_path(path: &str) -> Result<String, PathError> {
+    let decoded = percent_decode(path).ok_or(PathError::BadRequest(format!("Invalid percent encoding in {}", path)))?;
+
+    if decoded.iter().any(|byte| *byte == 0 || *byte == b'\\') {
+        return Err(PathError::BadRequest(format!("Invalid character in {}", path)));
+    }
+
+    return String::from_utf8(decoded).map_err(|_| PathError::BadRequest(format!("Path is not valid UTF-8 {}", path)));
+}
+
+//  Splits the path into its segments with "." and ".." applied
+//  A ".." that would climb above the root is refused rather than clamped
+fn remove_dot_segments(path: &str) -> Result<Vec<String>, PathError> {
+    if !path.starts_with('/') { return Err(PathError::BadRequest(format!("Path must start with / {}", path))); }
+
+    let mut segments: Vec<String> = Vec::new();
+
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => {
+                if segments.pop().is_none() { return Err(PathError::Forbidden(format!("{} climbs above the root", path))); }
+            },
+            _ => {
+                //  Anything that is not a plain name, such as a drive prefix on windows, could escape the root
+                if !matches!(Path::new(segment).components().next(), Some(Component::Normal(_))) {
+                    return Err(PathError::BadRequest(format!("Invalid segment {} in {}", segment, path)));
+                }
+                segments.push(segment.to_string());
+            },
+        }
+    }
+
+    return Ok(segments);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_dot_segments() {
+        assert_eq!(remove_dot_segments("/").unwrap(), Vec::<String>::new());
+        assert_eq!(remove_dot_segments("/a/./b/../c").unwrap(), vec!["a", "c"]);
+        assert_eq!(remove_dot_segments("//a//b/").unwrap(), vec!["a", "b"]);
+        assert_eq!(remove_dot_segments("/a/..").unwrap(), Vec::<String>::new());
+        assert_eq!(remove_dot_segments("/a/...").unwrap(), vec!["a", "..."]);
+    }
+
+    #[test]
+    fn refuses_climbing_above_the_root() {
+        assert!(matches!(remove_dot_segments("/.."), Err(PathError::Forbidden(_))));
+        assert!(matches!(remove_dot_segments("/a/../../b"), Err(PathError::Forbidden(_))));
+        assert!(matches!(remove_dot_segments("/./.."), Err(PathError::Forbidden(_))));
+    }
+
+    #[test]
+    fn refuses_relative_paths() {
+        assert!(matches!(remove_dot_segments("a/b"), Err(PathError::BadRequest(_))));
+        assert!(matches!(remove_dot_segments(""), Err(PathError::BadRequest(_))));
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(normalize_path("//evil.example").unwrap(), "/evil.example");
+        assert_eq!(normalize_path("/./a//b%20c/../d").unwrap(), "/a/d");
+        assert_eq!(normalize_path("/a%2Fb%3F").unwrap(), "/a/b%3F");
+        assert_eq!(normalize_path("/.").unwrap(), "/");
+        assert!(matches!(normalize_path("/.."), Err(PathError::Forbidden(_))));
+    }
+
+    #[test]
+    fn decodes_paths() {
+        assert_eq!(decode_path("/a%20b/%2e%2e").unwrap(), "/a b/..");
+        assert!(matches!(decode_path("/a%00"), Err(PathError::BadRequest(_))));
+        assert!(matches!(decode_path("/a%5Cb"), Err(PathError::BadRequest(_))));
+        assert!(matches!(decode_path("/%FF"), Err(PathError::BadRequest(_))));
+        assert!(matches!(decode_path("/%zz"), Err(PathError::BadRequest(_))));
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    fs::Metadata,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::http::{HttpMethod, HttpRequest};
+
+//  Validators describing the current version of a file
+pub struct Validators {
+    //  The quoted entity tag, prefixed with W/ when it is weak
+    pub etag: String,
+    //  Truncated to whole seconds as that is all an HTTP date can carry
+    pub lastmodified: Option<SystemTime>,
+}
+
+impl Validators {
+    //  Derives the validators from the file's size and modification time
+    pub fn from_metadata(metadata: &Metadata, weak: bool) -> Validators {
+        let modified = metadata.modified().ok().and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+        let nanos = modified.map(|modified| modified.as_nanos()).unwrap_or_default();
+        let prefix = if weak { "W/" } else { "" };
+
+        return Validators {
+            etag: format!("{}\"{:x}-{:x}\"", prefix, metadata.len(), nanos),
+            lastmodified: modified.map(|modified| UNIX_EPOCH + std::time::Duration::from_secs(modified.as_secs())),
+        };
+    }
+
+    pub fn get_last_modified(&self) -> Option<String> {
+        return self.lastmodified.map(httpdate::fmt_http_date);
+    }
+}
+
+//  What to do with a request once its preconditions have been checked
+pub enum Precondition {
+    //  Serve the request as normal
+    Proceed,
+    //  The client's cached copy is current, answer 304
+    NotModified,
+    //  A precondition does not hold, answer 412
+    Failed,
+}
+
+//  Checks the conditional headers of the request in the order given by RFC 9110 section 13.2.2
+pub fn evaluate_preconditions(request: &HttpRequest, validators: &Validators) -> Precondition {
+    let isgetorhead = matches!(request.method, HttpMethod::GET | HttpMethod::HEAD);
+
+    if let Some(ifmatch) = request.headers.get("if-match") {
+        if !matches_etag_list(ifmatch, &validators.etag, true) { return Precondition::Failed; }
+    } else if let Some(since) = request.headers.get("if-unmodified-since").and_then(|date| httpdate::parse_http_date(date).ok()) {
+        if validators.lastmodified.is_none_or(|lastmodified| lastmodified > since) { return Precondition::Failed; }
+    }
+
+    if let Some(ifnonematch) = request.headers.get("if-none-match") {
+        if matches_etag_list(ifnonematch, &validators.etag, false) {
+            return if isgetorhead { Precondition::NotModified } else { Precondition::Failed };
+        }
+    } else if let Some(since) = request.headers.get("if-modified-since").and_then(|date| httpdate::parse_http_date(date).ok()) {
+        if isgetorhead && validators.lastmodified.is_some_and(|lastmodified| lastmodified <= since) { return Precondition::NotModified; }
+    }
+
+    return Precondition::Proceed;
+}
+
+//  Checks whether the etag is in a header value such as "*" or W/"a", "b"
+//  Strong comparison needs both tags to be strong, weak comparison ignores the W/ prefix
+pub fn matches_etag_list(header: &str, etag: &str, strong: bool) -> bool {
+    if header.trim() == "*" { return true; }
+
+    return parse_etag_list(header).iter().any(|candidate| compare_etags(candidate, etag, strong));
+}
+
+pub fn compare_etags(a: &str, b: &str, strong: bool) -> bool {
+    if strong { return !a.starts_with("W/") && !b.starts_with("W/") && a == b; }
+
+    return a.trim_start_matches("W/") == b.trim_start_matches("W/");
+}
+
+//  Splits a list of entity tags, commas are allowed inside the quotes so they cannot simply be split on
+fn parse_etag_list(header: &str) -> Vec<String> {
+    let mut etags = Vec::new();
+    let mut rest = header.trim();
+
+    while !rest.is_empty() {
+        let weak = rest.starts_with("W/");
+        let quoted = rest.trim_start_matches("W/");
+
+        if !quoted.starts_with('"') { break; }
+
+        let end = match quoted[1..].find('"') {
+            Some(end) => end + 2,
+            None => break,
+        };
+
+        etags.push(format!("{}{}", if weak { "W/" } else { "" }, &quoted[..end]));
+        rest = quoted[end..].trim_start_matches([' ', '\t', ',']);
+    }
+
+    return etags;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const LASTMODIFIED: u64 = 1_000_000_000;
+
+    fn evaluate(method: &str, headers: &str) -> Precondition {
+        let request = format!("{} / HTTP/1.1\r\nHost: x{}", method, headers).parse::<HttpRequest>().unwrap();
+        let validators = Validators { etag: "\"abc\"".to_string(), lastmodified: Some(UNIX_EPOCH + Duration::from_secs(LASTMODIFIED)) };
+
+        return evaluate_preconditions(&request, &validators);
+    }
+
+    fn get_date(secs: u64) -> String {
+        return httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+
+    #[test]
+    fn proceeds_without_conditional_headers() {
+        assert!(matches!(evaluate("GET", ""), Precondition::Proceed));
+    }
+
+    #[test]
+    fn if_match_compares_strongly() {
+        assert!(matches!(evaluate("PUT", "\r\nIf-Match: \"abc\""), Precondition::Proceed));
+        assert!(matches!(evaluate("PUT", "\r\nIf-Match: *"), Precondition::Proceed));
+        assert!(matches!(evaluate("PUT", "\r\nIf-Match: \"x\", \"abc\""), Precondition::Proceed));
+        assert!(matches!(evaluate("PUT", "\r\nIf-Match: W/\"abc\""), Precondition::Failed));
+        assert!(matches!(evaluate("GET", "\r\nIf-Match: \"x\""), Precondition::Failed));
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        assert!(matches!(evaluate("GET", "\r\nIf-None-Match: W/\"abc\""), Precondition::NotModified));
+        assert!(matches!(evaluate("HEAD", "\r\nIf-None-Match: *"), Precondition::NotModified));
+        assert!(matches!(evaluate("GET", "\r\nIf-None-Match: \"x\""), Precondition::Proceed));
+        assert!(matches!(evaluate("PUT", "\r\nIf-None-Match: \"abc\""), Precondition::Failed));
+    }
+
+    #[test]
+    fn if_unmodified_since_is_ignored_with_if_match() {
+        let earlier = get_date(LASTMODIFIED - 1);
+
+        assert!(matches!(evaluate("PUT", &format!("\r\nIf-Unmodified-Since: {}", earlier)), Precondition::Failed));
+        assert!(matches!(evaluate("PUT", &format!("\r\nIf-Unmodified-Since: {}", get_date(LASTMODIFIED))), Precondition::Proceed));
+        assert!(matches!(evaluate("PUT", &format!("\r\nIf-Match: \"abc\"\r\nIf-Unmodified-Since: {}", earlier)), Precondition::Proceed));
+    }
+
+    #[test]
+    fn if_modified_since_is_ignored_with_if_none_match() {
+        let later = get_date(LASTMODIFIED + 1);
+
+        assert!(matches!(evaluate("GET", &format!("\r\nIf-Modified-Since: {}", later)), Precondition::NotModified));
+        assert!(matches!(evaluate("GET", &format!("\r\nIf-Modified-Since: {}", get_date(LASTMODIFIED))), Precondition::NotModified));
+        assert!(matches!(evaluate("GET", &format!("\r\nIf-Modified-Since: {}", get_date(LASTMODIFIED - 1))), Precondition::Proceed));
+        assert!(matches!(evaluate("GET", "\r\nIf-Modified-Since: yesterday"), Precondition::Proceed));
+        assert!(matches!(evaluate("PUT", &format!("\r\nIf-Modified-Since: {}", later)), Precondition::Proceed));
+        assert!(matches!(evaluate("GET", &format!("\r\nIf-None-Match: \"x\"\r\nIf-Modified-Since: {}", later)), Precondition::Proceed));
+    }
+
+    #[test]
+    fn parses_etag_lists_with_commas_inside_quotes() {
+        assert_eq!(parse_etag_list("\"a,b\", W/\"c\",\"d\""), vec!["\"a,b\"", "W/\"c\"", "\"d\""]);
+        assert_eq!(parse_etag_list("\"a\", junk, \"b\""), vec!["\"a\""]);
+        assert!(matches_etag_list("\"x\", \"a,b\"", "\"a,b\"", true));
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom, Take},
+    path::Path,
+};
+
+use crate::{
+    http::HttpRequest,
+    preconditions::{compare_etags, Validators},
+};
+
+//  More ranges than this in one request is treated as abuse and the whole file is sent instead
+const MAXRANGES: usize = 16;
+
+//  An inclusive byte range within a file
+#[derive(Clone, Copy)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn length(&self) -> u64 {
+        return self.end - self.start + 1;
+    }
+
+    pub fn get_content_range(&self, filelength: u64) -> String {
+        return format!("bytes {}-{}/{}", self.start, self.end, filelength);
+    }
+}
+
+//  What the Range header of a request asks for
+pub enum RangeRequest {
+    //  No usable Range header, send the whole file
+    Full,
+    //  One or more satisfiable ranges, in the order they were asked for
+    Partial(Vec<ByteRange>),
+    //  A valid Range header none of whose ranges overlap the file, answer 416
+    Unsatisfiable,
+}
+
+//  Works out which part of the file the request wants
+//  The Range header is ignored when If-Range no longer matches the file or the header is malformed
+pub fn get_range_request(request: &HttpRequest, validators: &Validators, filelength: u64) -> RangeRequest {
+    let range = match request.headers.get("range") {
+        Some(range) => range,
+        None => return RangeRequest::Full,
+    };
+
+    if let Some(ifrange) = request.headers.get("if-range") {
+        if !is_if_range_current(ifrange, validators) { return RangeRequest::Full; }
+    }
+
+    return match parse_range_header(range, filelength) {
+        Some(ranges) if ranges.is_empty() => RangeRequest::Unsatisfiable,
+        Some(ranges) => RangeRequest::Partial(ranges),
+        None => RangeRequest::Full,
+    };
+}
+
+//  If-Range holds either an entity tag, compared strongly, or a date that has to match exactly
+fn is_if_range_current(ifrange: &str, validators: &Validators) -> bool {
+    let ifrange = ifrange.trim();
+
+    if ifrange.starts_with('"') || ifrange.starts_with("W/") {
+        return compare_etags(ifrange, &validators.etag, true);
+    }
+
+    return match (httpdate::parse_http_date(ifrange), validators.lastmodified) {
+        (Ok(date), Some(lastmodified)) => date == lastmodified,
+        _ => false,
+    };
+}
+
+//  Parses "bytes=0-499, 500-, -200" into the ranges that overlap the file
+//  Returns None for a malformed header or one using a unit other than bytes
+fn parse_range_header(header: &str, filelength: u64) -> Option<Vec<ByteRange>> {
+    let (unit, specs) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") { return None; }
+
+    let specs = specs.split(',').map(|spec| spec.trim()).filter(|spec| !spec.is_empty()).collect::<Vec<&str>>();
+    if specs.is_empty() || specs.len() > MAXRANGES { return None; }
+
+    let mut ranges = Vec::new();
+
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+        //  parse alone would also take a sign, as in +1-2
+        if !first.bytes().chain(last.bytes()).all(|byte| byte.is_ascii_digit()) { return None; }
+
+        let range = if first.is_empty() {
+            //  A suffix range, the last n bytes of the file
+            let suffix = last.parse::<u64>().ok()?;
+            if suffix == 0 || filelength == 0 { continue; }
+            ByteRange { start: filelength.saturating_sub(suffix), end: filelength - 1 }
+        } else {
+            let start = first.parse::<u64>().ok()?;
+            let end = match last.is_empty() {
+                true => u64::MAX,
+                false => last.parse::<u64>().ok()?,
+            };
+            if end < start { return None; }
+            if start >= filelength { continue; }
+            ByteRange { start, end: end.min(filelength - 1) }
+        };
+
+        ranges.push(range);
+    }
+
+    return Some(ranges);
+}
+
+//  Limits the file to a reader over just the range
+pub fn open_range(mut file: File, range: &ByteRange) -> io::Result<Take<File>> {
+    file.seek(SeekFrom::Start(range.start))?;
+
+    return Ok(file.take(range.length()));
+}
+
+//  Builds a multipart/byteranges body from the ranges of the file
+//  Returns the reader and its exact length, so the body can still be sent with Content-Length
+pub fn open_multipart_ranges(path: &Path, ranges: &[ByteRange], contenttype: &str, filelength: u64, boundary: &str) -> io::Result<(MultipartReader, u64)> {
+    let mut parts: Vec<Box<dyn Read + Send>> = Vec::new();
+    let mut length = 0;
+
+    for range in ranges {
+        let header = format!("\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n", boundary, contenttype, range.get_content_range(filelength));
+        length += header.len() as u64 + range.length();
+        parts.push(Box::new(Cursor::new(header.into_bytes())));
+        //  Each part needs its own handle as clones of a file share one position
+        parts.push(Box::new(open_range(File::open(path)?, range)?));
+    }
+
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    length += closing.len() as u64;
+    parts.push(Box::new(Cursor::new(closing.into_bytes())));
+
+    return Ok((MultipartReader { parts, current: 0 }, length));
+}
+
+//  Reads each part in turn
+pub struct MultipartReader {
+    parts: Vec<Box<dyn Read + Send>>,
+    current: usize,
+}
+
+impl Read for MultipartReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current < self.parts.len() {
+            let read = self.parts[self.current].read(buf)?;
+            if read > 0 || buf.is_empty() { return Ok(read); }
+            self.current += 1;
+        }
+
+        return Ok(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(header: &str, filelength: u64) -> Option<Vec<(u64, u64)>> {
+        return parse_range_header(header, filelength).map(|ranges| ranges.iter().map(|range| (range.start, range.end)).collect());
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(parse("bytes=0-499", 1000), Some(vec![(0, 499)]));
+        assert_eq!(parse("bytes=500-", 1000), Some(vec![(500, 999)]));
+        assert_eq!(parse("bytes=-200", 1000), Some(vec![(800, 999)]));
+        assert_eq!(parse("BYTES = 0-0, 10-19 ,-1", 1000), Some(vec![(0, 0), (10, 19), (999, 999)]));
+    }
+
+    #[test]
+    fn clamps_ranges_to_the_file() {
+        assert_eq!(parse("bytes=900-5000", 1000), Some(vec![(900, 999)]));
+        assert_eq!(parse("bytes=-5000", 1000), Some(vec![(0, 999)]));
+        assert_eq!(parse("bytes=0-18446744073709551615", 1000), Some(vec![(0, 999)]));
+    }
+
+    #[test]
+    fn skips_ranges_outside_the_file() {
+        assert_eq!(parse("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(parse("bytes=-0", 1000), Some(vec![]));
+        assert_eq!(parse("bytes=0-10", 0), Some(vec![]));
+        assert_eq!(parse("bytes=2000-3000, 0-1", 1000), Some(vec![(0, 1)]));
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        assert_eq!(parse("items=0-1", 1000), None);
+        assert_eq!(parse("bytes 0-1", 1000), None);
+        assert_eq!(parse("bytes=", 1000), None);
+        assert_eq!(parse("bytes=abc", 1000), None);
+        assert_eq!(parse("bytes=5-2", 1000), None);
+        assert_eq!(parse("bytes=+1-2", 1000), None);
+        assert_eq!(parse("bytes=-", 1000), None);
+        assert_eq!(parse("bytes=0-99999999999999999999", 1000), None);
+    }
+
+    #[test]
+    fn rejects_too_many_ranges() {
+        let specs = vec!["0-0"; MAXRANGES];
+        assert_eq!(parse(&format!("bytes={}", specs.join(",")), 1000).map(|ranges| ranges.len()), Some(MAXRANGES));
+        assert_eq!(parse(&format!("bytes={},1-1", specs.join(",")), 1000), None);
+    }
+}
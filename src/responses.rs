@@ -0,0 +1,26 @@
+use log::*;
+use uuid::Uuid;
+
+use crate::http::{HttpBody, HttpResponse, HttpStatusCode};
+
+//  Builds a response with the body, Content-Type is only set when there is a body to describe
+pub fn create_response(sessionid: &Uuid, http_status_code: HttpStatusCode, contenttype: &str, responsebody: impl Into<HttpBody>) -> HttpResponse {
+    let responsebody = responsebody.into();
+
+    let bodylength = responsebody.length().map(|length| length.to_string()).unwrap_or("unknown".to_string());
+    info!("{},Sending {} response. Body length:{}", sessionid, http_status_code, bodylength);
+
+    let mut response = HttpResponse::new();
+    response.head.status = http_status_code;
+
+    if !responsebody.is_empty() {
+        response.head.headers.insert("Content-Type".to_string(), contenttype.to_string());
+        response.body = responsebody;
+    }
+
+    return response;
+}
+
+pub fn create_empty_response(sessionid: &Uuid, http_status_code: HttpStatusCode) -> HttpResponse {
+    return create_response(sessionid, http_status_code, "", HttpBody::Empty);
+}
@@ -0,0 +1,280 @@
+use std::str::FromStr;
+
+use crate::{
+    handler::Handler,
+    http::{percent_decode, HttpMethod, HttpRequest, HttpResponse, HttpStatusCode},
+    responses::create_empty_response,
+};
+
+//  Handles a request matched by a route, with the parameters taken from its path
+pub type RouteHandler = Box<dyn Fn(&HttpRequest, &PathParams) -> HttpResponse + Send + Sync>;
+
+//  One segment of a route pattern
+enum Segment {
+    //  Has to equal the request segment
+    Literal(String),
+    //  {name} captures a single segment
+    Param(String),
+    //  *name captures the rest of the path, which may be empty
+    Wildcard(String),
+}
+
+struct Route {
+    method: HttpMethod,
+    segments: Vec<Segment>,
+    handler: RouteHandler,
+}
+
+//  The parameters captured from the request path by a route pattern
+pub struct PathParams {
+    params: Vec<(String, String)>,
+}
+
+impl PathParams {
+    //  Gets the decoded parameter converted to the type, e.g. params.get::<u32>("id")
+    pub fn get<T: FromStr>(&self, name: &str) -> Result<T, String> {
+        let value = self.params.iter().find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+            .ok_or(format!("Missing path parameter {}", name))?;
+
+        return value.parse::<T>().map_err(|_| format!("Invalid path parameter {}: {}", name, value));
+    }
+}
+
+//  Dispatches requests to the handler registered for their method and path
+//  Routes are tried in the order they were registered and the first match wins
+pub struct Router {
+    routes: Vec<Route>,
+    //  Answers requests no route pattern matches, e.g. by serving files from the root
+    fallback: Option<Box<dyn Handler>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        return Router { routes: Vec::new(), fallback: None };
+    }
+
+    //  Registers a handler for the method and pattern, such as /users/{id} or /static/*rest
+    //  Panics when the pattern is malformed, as that is a mistake in the server's own setup
+    pub fn route(mut self, method: HttpMethod, pattern: &str, handler: impl Fn(&HttpRequest, &PathParams) -> HttpResponse + Send + Sync + 'static) -> Router {
+        let segments = parse_pattern(pattern).unwrap_or_else(|e| panic!("Invalid route pattern {}: {}", pattern, e));
+
+        self.routes.push(Route { method, segments, handler: Box::new(handler) });
+        return self;
+    }
+
+    //  Registers a GET handler, which also answers HEAD requests for the path
+    pub fn get(self, pattern: &str, handler: impl Fn(&HttpRequest, &PathParams) -> HttpResponse + Send + Sync + 'static) -> Router {
+        return self.route(HttpMethod::GET, pattern, handler);
+    }
+
+    pub fn post(self, pattern: &str, handler: impl Fn(&HttpRequest, &PathParams) -> HttpResponse + Send + Sync + 'static) -> Router {
+        return self.route(HttpMethod::POST, pattern, handler);
+    }
+
+    pub fn put(self, pattern: &str, handler: impl Fn(&HttpRequest, &PathParams) -> HttpResponse + Send + Sync + 'static) -> Router {
+        return self.route(HttpMethod::PUT, pattern, handler);
+    }
+
+    pub fn delete(self, pattern: &str, handler: impl Fn(&HttpRequest, &PathParams) -> HttpResponse + Send + Sync + 'static) -> Router {
+        return self.route(HttpMethod::DELETE, pattern, handler);
+    }
+
+    //  Sets the handler for requests no route pattern matches, without one they get a 404
+    pub fn fallback(mut self, handler: impl Handler + 'static) -> Router {
+        self.fallback = Some(Box::new(handler));
+        return self;
+    }
+
+    //  Calls the handler for the request
+    //  A path some route matches but not with this method gets a 405, or a 204 listing the methods for OPTIONS
+    pub fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        let mut allowed: Vec<HttpMethod> = Vec::new();
+
+        for route in &self.routes {
+            let params = match match_segments(&route.segments, &request.path) {
+                Some(params) => params,
+                None => continue,
+            };
+
+            if route.method == request.method || (route.method == HttpMethod::GET && request.method == HttpMethod::HEAD) {
+                return (route.handler)(request, &params);
+            }
+
+            if !allowed.contains(&route.method) { allowed.push(route.method); }
+        }
+
+        if allowed.is_empty() {
+            return match &self.fallback {
+                Some(fallback) => fallback.call(request),
+                None => create_empty_response(&request.sessionid, HttpStatusCode::NotFound),
+            };
+        }
+
+        if allowed.contains(&HttpMethod::GET) { allowed.push(HttpMethod::HEAD); }
+        allowed.push(HttpMethod::OPTIONS);
+        let allow = allowed.iter().map(|method| method.to_string()).collect::<Vec<String>>().join(", ");
+
+        let status = if request.method == HttpMethod::OPTIONS { HttpStatusCode::NoContent } else { HttpStatusCode::MethodNotAllowed };
+        let mut response = create_empty_response(&request.sessionid, status);
+        response.head.headers.insert("Allow".to_string(), allow);
+
+        return response;
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        return Router::new();
+    }
+}
+
+impl Handler for Router {
+    fn call(&self, request: &HttpRequest) -> HttpResponse {
+        return self.handle(request);
+    }
+}
+
+//  Splits a pattern into segments, a wildcard is only allowed as the last one
+fn parse_pattern(pattern: &str) -> Result<Vec<Segment>, String> {
+    if !pattern.starts_with('/') { return Err("Pattern must start with /".to_string()); }
+
+    let parts = pattern[1..].split('/').collect::<Vec<&str>>();
+    let mut segments = Vec::new();
+
+    for (index, part) in parts.iter().enumerate() {
+        let segment = if let Some(name) = part.strip_prefix('{').and_then(|part| part.strip_suffix('}')) {
+            if name.is_empty() { return Err("Parameter has no name".to_string()); }
+            Segment::Param(name.to_string())
+        } else if let Some(name) = part.strip_prefix('*') {
+            if name.is_empty() { return Err("Wildcard has no name".to_string()); }
+            if index + 1 != parts.len() { return Err("Wildcard must be the last segment".to_string()); }
+            Segment::Wildcard(name.to_string())
+        } else {
+            Segment::Literal(part.to_string())
+        };
+
+        segments.push(segment);
+    }
+
+    return Ok(segments);
+}
+
+//  Matches the request path against the segments, capturing the parameters
+//  Captured values are percent-decoded, a path that does not decode to UTF-8 matches nothing
+fn match_segments(segments: &[Segment], path: &str) -> Option<PathParams> {
+    let parts = path.strip_prefix('/')?.split('/').collect::<Vec<&str>>();
+    let mut params = Vec::new();
+
+    for (index, segment) in segments.iter().enumerate() {
+        match segment {
+            Segment::Literal(literal) => {
+                if decode_segment(parts.get(index)?)? != *literal { return None; }
+            },
+            Segment::Param(name) => {
+                let part = parts.get(index)?;
+                if part.is_empty() { return None; }
+                params.push((name.clone(), decode_segment(part)?));
+            },
+            Segment::Wildcard(name) => {
+                let rest = parts.get(index..).unwrap_or_default().join("/");
+                params.push((name.clone(), decode_segment(&rest)?));
+                return Some(PathParams { params });
+            },
+        }
+    }
+
+    if parts.len() != segments.len() { return None; }
+
+    return Some(PathParams { params });
+}
+
+fn decode_segment(segment: &str) -> Option<String> {
+    return String::from_utf8(percent_decode(segment)?).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn match_path(pattern: &str, path: &str) -> Option<PathParams> {
+        return match_segments(&parse_pattern(pattern).unwrap(), path);
+    }
+
+    fn get_param(params: &PathParams, name: &str) -> String {
+        return params.get::<String>(name).unwrap();
+    }
+
+    #[test]
+    fn matches_literal_segments() {
+        assert!(match_path("/", "/").is_some());
+        assert!(match_path("/users/list", "/users/list").is_some());
+        assert!(match_path("/users/list", "/users%2Flist").is_none());
+        assert!(match_path("/a%20b", "/a%20b").is_none());
+        assert!(match_path("/a b", "/a%20b").is_some());
+        assert!(match_path("/users/list", "/users").is_none());
+        assert!(match_path("/users/list", "/users/list/").is_none());
+        assert!(match_path("/users", "users").is_none());
+    }
+
+    #[test]
+    fn captures_parameters() {
+        let params = match_path("/users/{id}/files/{name}", "/users/42/files/a%20b.txt").unwrap();
+
+        assert_eq!(params.get::<u32>("id"), Ok(42));
+        assert_eq!(get_param(&params, "name"), "a b.txt");
+        assert!(params.get::<u32>("name").is_err());
+        assert!(params.get::<u32>("missing").is_err());
+
+        assert!(match_path("/users/{id}", "/users/").is_none());
+        assert!(match_path("/users/{id}", "/users/%FF").is_none());
+    }
+
+    #[test]
+    fn captures_the_rest_of_the_path() {
+        assert_eq!(get_param(&match_path("/static/*rest", "/static/css/a%2Fb.css").unwrap(), "rest"), "css/a/b.css");
+        assert_eq!(get_param(&match_path("/static/*rest", "/static/").unwrap(), "rest"), "");
+        assert_eq!(get_param(&match_path("/static/*rest", "/static").unwrap(), "rest"), "");
+        assert!(match_path("/static/*rest", "/other/a").is_none());
+    }
+
+    #[test]
+    fn dispatches_on_method() {
+        let router = Router::new()
+            .get("/items/{id}", |request, _| create_empty_response(&request.sessionid, HttpStatusCode::Ok))
+            .delete("/items/{id}", |request, _| create_empty_response(&request.sessionid, HttpStatusCode::NoContent));
+        let handle = |requestline: &str| router.handle(&format!("{}\r\nHost: x", requestline).parse::<HttpRequest>().unwrap());
+
+        assert!(matches!(handle("GET /items/1 HTTP/1.1").head.status, HttpStatusCode::Ok));
+        assert!(matches!(handle("HEAD /items/1 HTTP/1.1").head.status, HttpStatusCode::Ok));
+        assert!(matches!(handle("DELETE /items/1 HTTP/1.1").head.status, HttpStatusCode::NoContent));
+        assert!(matches!(handle("GET /other HTTP/1.1").head.status, HttpStatusCode::NotFound));
+
+        let response = handle("POST /items/1 HTTP/1.1");
+        assert!(matches!(response.head.status, HttpStatusCode::MethodNotAllowed));
+        assert_eq!(response.head.get_header("Allow").map(String::as_str), Some("GET, DELETE, HEAD, OPTIONS"));
+
+        let response = handle("OPTIONS /items/1 HTTP/1.1");
+        assert!(matches!(response.head.status, HttpStatusCode::NoContent));
+        assert!(response.head.get_header("Allow").is_some());
+    }
+
+    #[test]
+    fn passes_unmatched_paths_to_the_fallback() {
+        let router = Router::new()
+            .post("/items", |request, _| create_empty_response(&request.sessionid, HttpStatusCode::Created))
+            .fallback(|request: &HttpRequest| create_empty_response(&request.sessionid, HttpStatusCode::Gone));
+        let handle = |requestline: &str| router.handle(&format!("{}\r\nHost: x", requestline).parse::<HttpRequest>().unwrap());
+
+        assert!(matches!(handle("GET /other HTTP/1.1").head.status, HttpStatusCode::Gone));
+        assert!(matches!(handle("GET /items HTTP/1.1").head.status, HttpStatusCode::MethodNotAllowed));
+    }
+
+    #[test]
+    fn rejects_malformed_patterns() {
+        assert!(parse_pattern("users").is_err());
+        assert!(parse_pattern("/users/{}").is_err());
+        assert!(parse_pattern("/static/*").is_err());
+        assert!(parse_pattern("/static/*rest/more").is_err());
+    }
+}
@@ -0,0 +1,463 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::*;
+use uuid::Uuid;
+
+use crate::{
+    compression::CompressionConfig,
+    config::ServerConfig,
+    connection::{handle_incoming_connection, reject_connection},
+    eventloop::{EventLoops, IoMode},
+    handler::Handler,
+    http::RequestLimits,
+    middleware::{CompressionMiddleware, LoggingMiddleware, Middleware, Pipeline},
+    pathresolver::SymlinkPolicy,
+    shutdown::{Connections, ShutdownHandle},
+    staticfiles::StaticFiles,
+    threads::{Job, OverloadPolicy, PoolMonitor, PoolStats, ThreadPool},
+};
+
+//  Settings a ServerBuilder starts with, public so that anything configuring a server falls back to the same values
+pub const DEFAULTIP: &str = "127.0.0.1";
+pub const DEFAULTPORT: u16 = 4221;
+pub const DEFAULTMINTHREADS: usize = 4;
+pub const DEFAULTMAXTHREADS: usize = 64;
+pub const DEFAULTTHREADIDLETIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULTEVENTLOOPTHREADS: usize = 2;
+pub const DEFAULTKEEPALIVETIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULTKEEPALIVEMAXREQUESTS: usize = 100;
+pub const DEFAULTHEADERTIMEOUT: Duration = Duration::from_secs(20);
+pub const DEFAULTMINBODYRATE: u64 = 500;
+pub const DEFAULTWRITETIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULTQUEUECAPACITY: usize = 128;
+pub const DEFAULTGRACEPERIOD: Duration = Duration::from_secs(10);
+pub const DEFAULTSERVERNAME: &str = concat!("simple-http-server/", env!("CARGO_PKG_VERSION"));
+const DEFAULTRETRYAFTER: Duration = Duration::from_secs(1);
+
+//  A bound server, ready to accept connections with run or spawn
+pub struct Server {
+    config: Arc<ServerConfig>,
+    pipeline: Arc<Pipeline>,
+    listener: TcpListener,
+    threadpool: Arc<ThreadPool>,
+    //  Watches the connections when the server runs in event loop mode, otherwise each connection has a worker
+    eventloops: Option<EventLoops>,
+    connections: Arc<Connections>,
+    shutdown: ShutdownHandle,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        return ServerBuilder::new();
+    }
+
+    //  The address the server is listening on, with the real port when it was bound to port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        return self.listener.local_addr();
+    }
+
+    //  A handle that stops the server, e.g. from a signal handler
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        return self.shutdown.clone();
+    }
+
+    //  Reads the thread pool's stats while the server runs, from any thread
+    pub fn pool_monitor(&self) -> PoolMonitor {
+        return self.threadpool.monitor();
+    }
+
+    //  Accepts connections on the current thread, handing each to the thread pool
+    //  Returns once a shutdown has been asked for and the open connections have finished or been closed
+    pub fn run(self) {
+        info!("{},Started web server on {}", Uuid::nil(), self.local_addr().map(|address| address.to_string()).unwrap_or_default());
+
+        for stream in self.listener.incoming() {
+            if self.shutdown.is_requested() { break; }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("{},Error: {}", Uuid::nil(), e);
+                    continue;
+                },
+            };
+
+            let sessionid = Uuid::new_v4();
+            self.connections.register(&sessionid, &stream);
+
+            if let Some(eventloops) = &self.eventloops {
+                eventloops.dispatch(sessionid, stream);
+                continue;
+            }
+
+            self.threadpool.execute(ConnectionJob {
+                sessionid,
+                config: self.config.clone(),
+                pipeline: self.pipeline.clone(),
+                connections: self.connections.clone(),
+                stream,
+            });
+        }
+
+        info!("{},Shutting down, waiting up to {}s for open connections", Uuid::nil(), self.config.graceperiod.as_secs());
+        drop(self.listener);
+
+        let summary = self.connections.shutdown(self.config.graceperiod);
+        info!("{},Shut down in {}ms. Connections accepted:{} in flight:{} force closed:{}",
+            Uuid::nil(), summary.elapsed.as_millis(), summary.accepted, summary.inflight, summary.forceclosed);
+        drop(self.eventloops);
+
+        let stats = self.threadpool.monitor().stats();
+        info!("{},Thread pool. Workers peak:{} spawned:{} retired:{} panicked:{} jobs completed:{}",
+            Uuid::nil(), stats.peakworkers, stats.spawned, stats.retired, stats.panicked, stats.completed);
+    }
+
+    //  Runs the server on a thread of its own, for embedding it in another program or a test
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let address = self.local_addr()?;
+        let shutdown = self.shutdown_handle();
+        let monitor = self.pool_monitor();
+        let thread = thread::Builder::new().name("simple-http-server".to_string()).spawn(move || self.run())?;
+
+        return Ok(ServerHandle { address, shutdown, monitor, thread });
+    }
+}
+
+//  An accepted connection waiting for a worker
+struct ConnectionJob {
+    sessionid: Uuid,
+    config: Arc<ServerConfig>,
+    pipeline: Arc<Pipeline>,
+    connections: Arc<Connections>,
+    stream: TcpStream,
+}
+
+impl Job for ConnectionJob {
+    fn run(self: Box<Self>) {
+        let _registration = Registration { sessionid: self.sessionid, connections: self.connections.clone() };
+        handle_incoming_connection(&self.sessionid, &self.config, &self.pipeline, &self.connections, &self.stream);
+    }
+
+    fn shed(self: Box<Self>) {
+        let _registration = Registration { sessionid: self.sessionid, connections: self.connections.clone() };
+        reject_connection(&self.sessionid, &self.config, &self.stream, self.config.retryafter);
+    }
+}
+
+//  Unregisters the connection once it is done with, even when serving it panicked
+struct Registration {
+    sessionid: Uuid,
+    connections: Arc<Connections>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if thread::panicking() { error!("{},Connection dropped after a panic", self.sessionid); }
+        self.connections.unregister(&self.sessionid);
+    }
+}
+
+//  A server running on its own thread
+pub struct ServerHandle {
+    address: SocketAddr,
+    shutdown: ShutdownHandle,
+    monitor: PoolMonitor,
+    thread: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    //  The address the server is listening on
+    pub fn address(&self) -> SocketAddr {
+        return self.address;
+    }
+
+    //  What the server's thread pool is doing right now
+    pub fn pool_stats(&self) -> PoolStats {
+        return self.monitor.stats();
+    }
+
+    //  Stops the server and waits for it to finish
+    pub fn shutdown(self) {
+        self.shutdown.shutdown();
+        self.join();
+    }
+
+    //  Waits for the server thread to finish
+    pub fn join(self) {
+        if self.thread.join().is_err() { error!("{},Server thread panicked", Uuid::nil()); }
+    }
+}
+
+//  Settings for a server, given as Server::builder().root("public").bind("127.0.0.1:0").build()
+//  Either a root to serve files from or a handler is needed, not both
+//  To serve routes and files together, give a Router whose fallback is the StaticFiles as the handler
+pub struct ServerBuilder {
+    root: Option<String>,
+    address: String,
+    minthreads: usize,
+    maxthreads: usize,
+    threadidletimeout: Duration,
+    iomode: IoMode,
+    eventloopthreads: usize,
+    queuecapacity: usize,
+    overloadpolicy: OverloadPolicy,
+    retryafter: Duration,
+    keepalivetimeout: Duration,
+    keepalivemaxrequests: usize,
+    headertimeout: Duration,
+    minbodyrate: u64,
+    writetimeout: Duration,
+    limits: RequestLimits,
+    servername: Option<String>,
+    graceperiod: Duration,
+    compression: CompressionConfig,
+    handler: Option<Box<dyn Handler>>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl ServerBuilder {
+    fn new() -> ServerBuilder {
+        return ServerBuilder {
+            root: None,
+            address: format!("{}:{}", DEFAULTIP, DEFAULTPORT),
+            minthreads: DEFAULTMINTHREADS,
+            maxthreads: DEFAULTMAXTHREADS,
+            threadidletimeout: DEFAULTTHREADIDLETIMEOUT,
+            iomode: IoMode::default(),
+            eventloopthreads: DEFAULTEVENTLOOPTHREADS,
+            queuecapacity: DEFAULTQUEUECAPACITY,
+            overloadpolicy: OverloadPolicy::default(),
+            retryafter: DEFAULTRETRYAFTER,
+            keepalivetimeout: DEFAULTKEEPALIVETIMEOUT,
+            keepalivemaxrequests: DEFAULTKEEPALIVEMAXREQUESTS,
+            headertimeout: DEFAULTHEADERTIMEOUT,
+            minbodyrate: DEFAULTMINBODYRATE,
+            writetimeout: DEFAULTWRITETIMEOUT,
+            limits: RequestLimits::default(),
+            servername: Some(DEFAULTSERVERNAME.to_string()),
+            graceperiod: DEFAULTGRACEPERIOD,
+            compression: CompressionConfig::default(),
+            handler: None,
+            middleware: Vec::new(),
+        };
+    }
+
+    //  Serves the files under the directory
+    pub fn root(mut self, root: &str) -> ServerBuilder {
+        self.root = Some(root.to_string());
+        return self;
+    }
+
+    //  The address to listen on, port 0 picks a free port
+    pub fn bind(mut self, address: &str) -> ServerBuilder {
+        self.address = address.to_string();
+        return self;
+    }
+
+    //  A fixed number of connections served at once, the pool neither grows nor shrinks
+    pub fn threads(mut self, threads: usize) -> ServerBuilder {
+        self.minthreads = threads;
+        self.maxthreads = threads;
+        return self;
+    }
+
+    //  Worker threads kept running however quiet the server is
+    pub fn minthreads(mut self, minthreads: usize) -> ServerBuilder {
+        self.minthreads = minthreads;
+        return self;
+    }
+
+    //  Worker threads the pool grows to while connections are waiting
+    pub fn maxthreads(mut self, maxthreads: usize) -> ServerBuilder {
+        self.maxthreads = maxthreads;
+        return self;
+    }
+
+    //  How long a worker above the minimum waits for a connection before it exits
+    pub fn threadidletimeout(mut self, threadidletimeout: Duration) -> ServerBuilder {
+        self.threadidletimeout = threadidletimeout;
+        return self;
+    }
+
+    //  Whether each connection has a worker to itself or event loops watch the connections between requests
+    pub fn iomode(mut self, iomode: IoMode) -> ServerBuilder {
+        self.iomode = iomode;
+        return self;
+    }
+
+    //  How many event loops share the connections in event loop mode
+    pub fn eventloopthreads(mut self, eventloopthreads: usize) -> ServerBuilder {
+        self.eventloopthreads = eventloopthreads;
+        return self;
+    }
+
+    //  How many accepted connections may wait for a worker before the overload policy applies
+    pub fn queuecapacity(mut self, queuecapacity: usize) -> ServerBuilder {
+        self.queuecapacity = queuecapacity;
+        return self;
+    }
+
+    //  What happens to connections that arrive while the queue is full
+    //  In event loop mode it is requests that are queued, and Block rejects them as an event loop cannot wait
+    pub fn overloadpolicy(mut self, overloadpolicy: OverloadPolicy) -> ServerBuilder {
+        self.overloadpolicy = overloadpolicy;
+        return self;
+    }
+
+    //  Sent in the Retry-After header of the 503 a shed connection gets
+    pub fn retryafter(mut self, retryafter: Duration) -> ServerBuilder {
+        self.retryafter = retryafter;
+        return self;
+    }
+
+    pub fn keepalivetimeout(mut self, keepalivetimeout: Duration) -> ServerBuilder {
+        self.keepalivetimeout = keepalivetimeout;
+        return self;
+    }
+
+    //  How many requests a connection is used for before it is closed, at least 1
+    pub fn keepalivemaxrequests(mut self, keepalivemaxrequests: usize) -> ServerBuilder {
+        self.keepalivemaxrequests = keepalivemaxrequests;
+        return self;
+    }
+
+    //  How long a client has to send a request head once it has started it, or its first request once connected
+    //  A client that runs out of time is answered 408
+    pub fn headertimeout(mut self, headertimeout: Duration) -> ServerBuilder {
+        self.headertimeout = headertimeout;
+        return self;
+    }
+
+    //  Slowest rate in bytes a second a request body may arrive at once past its first few seconds, 0 for no limit
+    pub fn minbodyrate(mut self, minbodyrate: u64) -> ServerBuilder {
+        self.minbodyrate = minbodyrate;
+        return self;
+    }
+
+    //  How long sending a response may go without the client taking any of it before the connection is closed
+    pub fn writetimeout(mut self, writetimeout: Duration) -> ServerBuilder {
+        self.writetimeout = writetimeout;
+        return self;
+    }
+
+    //  How large the request line, headers and body of a request may be
+    pub fn limits(mut self, limits: RequestLimits) -> ServerBuilder {
+        self.limits = limits;
+        return self;
+    }
+
+    //  Value of the Server header, None to leave it out
+    pub fn servername(mut self, servername: Option<&str>) -> ServerBuilder {
+        self.servername = servername.map(|servername| servername.to_string());
+        return self;
+    }
+
+    //  How long in-flight requests are given to finish on shutdown before their connections are closed
+    pub fn graceperiod(mut self, graceperiod: Duration) -> ServerBuilder {
+        self.graceperiod = graceperiod;
+        return self;
+    }
+
+    pub fn compression(mut self, compression: CompressionConfig) -> ServerBuilder {
+        self.compression = compression;
+        return self;
+    }
+
+    //  Answers every request, in place of serving files from a root
+    pub fn handler(mut self, handler: impl Handler + 'static) -> ServerBuilder {
+        self.handler = Some(Box::new(handler));
+        return self;
+    }
+
+    //  Adds middleware around the handler, inside the built in logging and compression
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> ServerBuilder {
+        self.middleware.push(Box::new(middleware));
+        return self;
+    }
+
+    //  Binds the address and starts the thread pool
+    pub fn build(self) -> Result<Server, String> {
+        if self.keepalivemaxrequests == 0 { return Err("At least one request per connection is needed".to_string()); }
+
+        let handler: Box<dyn Handler> = match (self.handler, &self.root) {
+            (Some(handler), None) => handler,
+            (None, Some(root)) => Box::new(StaticFiles::new(root, SymlinkPolicy::default())?),
+            (Some(_), Some(_)) => return Err("A root and a handler were both given, make the StaticFiles the fallback of a Router instead".to_string()),
+            (None, None) => return Err("A root or a handler is needed".to_string()),
+        };
+
+        let mut middleware: Vec<Box<dyn Middleware>> = vec![Box::new(LoggingMiddleware), Box::new(CompressionMiddleware::new(self.compression))];
+        middleware.extend(self.middleware);
+
+        let listener = TcpListener::bind(&self.address).map_err(|e| format!("Could not bind {}: {}", self.address, e))?;
+        let threadpool = ThreadPool::new(self.minthreads, self.maxthreads, self.threadidletimeout, self.queuecapacity, self.overloadpolicy).map_err(|e| format!("Could not create thread pool: {:?}", e))?;
+        let threadpool = Arc::new(threadpool);
+
+        let config = ServerConfig {
+            keepalivetimeout: self.keepalivetimeout,
+            keepalivemaxrequests: self.keepalivemaxrequests,
+            headertimeout: self.headertimeout,
+            minbodyrate: self.minbodyrate,
+            writetimeout: self.writetimeout,
+            limits: self.limits,
+            servername: self.servername,
+            graceperiod: self.graceperiod,
+            retryafter: self.retryafter,
+        };
+        let address = listener.local_addr().map_err(|e| format!("Could not get the bound address: {}", e))?;
+
+        let config = Arc::new(config);
+        let pipeline = Arc::new(Pipeline::from_parts(middleware, handler));
+        let connections = Arc::new(Connections::new());
+
+        let eventloops = match self.iomode {
+            IoMode::Threaded => None,
+            IoMode::EventLoop if self.eventloopthreads == 0 => return Err("At least one event loop thread is needed".to_string()),
+            IoMode::EventLoop => Some(EventLoops::start(self.eventloopthreads, &config, &pipeline, &connections, &threadpool)
+                .map_err(|e| format!("Could not start the event loops: {}", e))?),
+        };
+
+        return Ok(Server {
+            config,
+            pipeline,
+            listener,
+            threadpool,
+            eventloops,
+            connections,
+            shutdown: ShutdownHandle::new(address),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http::{HttpRequest, HttpResponse, HttpStatusCode}, responses::create_empty_response};
+
+    fn answer(request: &HttpRequest) -> HttpResponse {
+        return create_empty_response(&request.sessionid, HttpStatusCode::NoContent);
+    }
+
+    #[test]
+    fn needs_exactly_one_of_root_and_handler() {
+        let root = std::env::temp_dir();
+        let root = root.to_str().unwrap();
+
+        assert!(Server::builder().bind("127.0.0.1:0").build().is_err());
+        assert!(Server::builder().bind("127.0.0.1:0").root(root).handler(answer).build().is_err());
+        assert!(Server::builder().bind("127.0.0.1:0").root(root).build().is_ok());
+        assert!(Server::builder().bind("127.0.0.1:0").handler(answer).build().is_ok());
+    }
+
+    #[test]
+    fn refuses_connections_serving_no_requests() {
+        assert!(Server::builder().bind("127.0.0.1:0").handler(answer).keepalivemaxrequests(0).build().is_err());
+        assert!(Server::builder().bind("127.0.0.1:0").handler(answer).keepalivemaxrequests(1).build().is_ok());
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use log::*;
+use uuid::Uuid;
+
+//  Asks a running server to shut down, it can be cloned and sent to other threads such as a signal handler
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    address: SocketAddr,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(address: SocketAddr) -> ShutdownHandle {
+        return ShutdownHandle { requested: Arc::new(AtomicBool::new(false)), address };
+    }
+
+    //  Stops the server accepting connections, in-flight requests are still allowed to finish
+    pub fn shutdown(&self) {
+        if self.requested.swap(true, Ordering::SeqCst) { return; }
+
+        //  The accept loop is blocked waiting for a connection, so make one to wake it up
+        let _ = TcpStream::connect_timeout(&get_wake_address(self.address), Duration::from_secs(1));
+    }
+
+    pub fn is_requested(&self) -> bool {
+        return self.requested.load(Ordering::SeqCst);
+    }
+}
+
+//  A listener bound to every interface cannot be connected to at that address, so use loopback instead
+fn get_wake_address(address: SocketAddr) -> SocketAddr {
+    let ip = match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+
+    return SocketAddr::new(ip, address.port());
+}
+
+//  A connection the server is serving
+struct Connection {
+    stream: TcpStream,
+    //  Whether a request is arriving or being answered, as opposed to waiting for the next one
+    busy: bool,
+}
+
+//  What happened to the connections still open when the server shut down
+pub(crate) struct ShutdownSummary {
+    pub accepted: usize,
+    pub inflight: usize,
+    pub forceclosed: usize,
+    pub elapsed: Duration,
+}
+
+//  Tracks open connections so a shutdown can wait for them to finish
+pub(crate) struct Connections {
+    active: Mutex<HashMap<Uuid, Connection>>,
+    changed: Condvar,
+    shuttingdown: AtomicBool,
+    accepted: AtomicUsize,
+}
+
+impl Connections {
+    pub fn new() -> Connections {
+        return Connections { active: Mutex::new(HashMap::new()), changed: Condvar::new(), shuttingdown: AtomicBool::new(false), accepted: AtomicUsize::new(0) };
+    }
+
+    //  A panicking connection must not stop the rest being tracked, so a poisoned lock is still used
+    fn lock(&self) -> MutexGuard<'_, HashMap<Uuid, Connection>> {
+        return self.active.lock().unwrap_or_else(|e| e.into_inner());
+    }
+
+    pub fn register(&self, sessionid: &Uuid, stream: &TcpStream) {
+        self.accepted.fetch_add(1, Ordering::SeqCst);
+
+        match stream.try_clone() {
+            Ok(stream) => { self.lock().insert(*sessionid, Connection { stream, busy: false }); },
+            Err(e) => error!("{},Could not track connection: {}", sessionid, e),
+        }
+    }
+
+    pub fn unregister(&self, sessionid: &Uuid) {
+        self.lock().remove(sessionid);
+        self.changed.notify_all();
+    }
+
+    pub fn set_busy(&self, sessionid: &Uuid, busy: bool) {
+        if let Some(connection) = self.lock().get_mut(sessionid) { connection.busy = busy; }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        return self.shuttingdown.load(Ordering::SeqCst);
+    }
+
+    //  Lets busy connections finish their current request, then closes whatever is left after the grace period
+    pub fn shutdown(&self, graceperiod: Duration) -> ShutdownSummary {
+        let started = Instant::now();
+        let mut active = self.lock();
+
+        self.shuttingdown.store(true, Ordering::SeqCst);
+        let inflight = active.values().filter(|connection| connection.busy).count();
+
+        //  Idle connections are only waiting for a request that will not be served, so wake them to close
+        for connection in active.values().filter(|connection| !connection.busy) {
+            let _ = connection.stream.shutdown(Shutdown::Read);
+        }
+
+        while !active.is_empty() {
+            let remaining = graceperiod.saturating_sub(started.elapsed());
+            if remaining.is_zero() { break; }
+            active = self.changed.wait_timeout(active, remaining).unwrap_or_else(|e| e.into_inner()).0;
+        }
+
+        let forceclosed = active.len();
+        for (sessionid, connection) in active.iter() {
+            warn!("{},Closing connection still open after the grace period", sessionid);
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+
+        return ShutdownSummary { accepted: self.accepted.load(Ordering::SeqCst), inflight, forceclosed, elapsed: started.elapsed() };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener, sync::Arc, thread};
+
+    use super::*;
+
+    //  A connected pair of sockets, the server's end first
+    fn connect(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        return (server, client);
+    }
+
+    #[test]
+    fn wakes_idle_connections_and_waits_for_busy_ones() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connections = Arc::new(Connections::new());
+        let (idle, _idleclient) = connect(&listener);
+        let (busy, _busyclient) = connect(&listener);
+        let (idleid, busyid) = (Uuid::new_v4(), Uuid::new_v4());
+
+        connections.register(&idleid, &idle);
+        connections.register(&busyid, &busy);
+        connections.set_busy(&busyid, true);
+
+        let worker = {
+            let connections = connections.clone();
+            thread::spawn(move || {
+                //  The idle connection's read ends as soon as the shutdown starts
+                assert_eq!((&idle).read(&mut [0; 16]).unwrap(), 0);
+                assert!(connections.is_shutting_down());
+                connections.unregister(&idleid);
+
+                thread::sleep(Duration::from_millis(100));
+                connections.unregister(&busyid);
+            })
+        };
+
+        let summary = connections.shutdown(Duration::from_secs(10));
+        worker.join().unwrap();
+
+        assert_eq!(summary.accepted, 2);
+        assert_eq!(summary.inflight, 1);
+        assert_eq!(summary.forceclosed, 0);
+        assert!(summary.elapsed < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn force_closes_connections_after_the_grace_period() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connections = Connections::new();
+        let (busy, mut client) = connect(&listener);
+        let sessionid = Uuid::new_v4();
+
+        connections.register(&sessionid, &busy);
+        connections.set_busy(&sessionid, true);
+
+        let summary = connections.shutdown(Duration::from_millis(100));
+
+        assert_eq!(summary.inflight, 1);
+        assert_eq!(summary.forceclosed, 1);
+        assert!(summary.elapsed >= Duration::from_millis(100));
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn shutdown_handle_wakes_the_accept_loop_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let handle = ShutdownHandle::new(listener.local_addr().unwrap());
+        assert!(!handle.is_requested());
+
+        let clone = handle.clone();
+        thread::spawn(move || clone.shutdown());
+        listener.accept().unwrap();
+        assert!(handle.is_requested());
+
+        handle.shutdown();
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_err());
+    }
+
+    #[test]
+    fn wakes_unspecified_addresses_on_loopback() {
+        assert_eq!(get_wake_address("0.0.0.0:80".parse().unwrap()), "127.0.0.1:80".parse().unwrap());
+        assert_eq!(get_wake_address("[::]:80".parse().unwrap()), "[::1]:80".parse().unwrap());
+        assert_eq!(get_wake_address("10.0.0.1:80".parse().unwrap()), "10.0.0.1:80".parse().unwrap());
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    fs::{File, Metadata},
+    path::{Path, PathBuf},
+};
+
+use log::*;
+use uuid::Uuid;
+
+use crate::{
+    compression::{self, ContentCoding},
+    directorylisting::{self, SortKey},
+    handler::Handler,
+    http::{percent_decode, percent_encode_segment, HttpBody, HttpMethod, HttpRequest, HttpResponse, HttpStatusCode, Representation},
+    mimetypes::MimeTypes,
+    pathresolver::{normalize_path, PathError, PathResolver, SymlinkPolicy},
+    preconditions::{evaluate_preconditions, Precondition, Validators},
+    ranges::{self, RangeRequest},
+    responses::{create_empty_response, create_response},
+};
+
+const HTMLCONTENTTYPE: &str = "text/html; charset=utf-8";
+const JSONCONTENTTYPE: &str = "application/json";
+//  Methods supported on every file and directory, sent in the Allow header
+const ALLOWEDMETHODS: &str = "GET, HEAD, OPTIONS";
+//  File served when a directory is requested
+const DIRECTORYINDEX: &str = "index.html";
+
+//  Serves the files under a root directory
+//  Use it as the handler of a server, or as the fallback of a router so it answers whatever no route matches
+pub struct StaticFiles {
+    //  Confines request paths to the root
+    pathresolver: PathResolver,
+    mimetypes: MimeTypes,
+    //  List directories that have no index.html
+    autoindex: bool,
+    //  Include dot files in directory listings
+    showhidden: bool,
+    //  Mark file ETags as weak, for content that is equivalent but not byte for byte identical
+    weaketags: bool,
+    //  Look for precompressed siblings such as app.js.br when serving app.js
+    precompressed: bool,
+}
+
+impl StaticFiles {
+    //  Fails when the root does not exist
+    pub fn new(root: &str, symlinkpolicy: SymlinkPolicy) -> Result<StaticFiles, String> {
+        return Ok(StaticFiles {
+            pathresolver: PathResolver::new(root, symlinkpolicy)?,
+            mimetypes: MimeTypes::new(),
+            autoindex: false,
+            showhidden: false,
+            weaketags: false,
+            precompressed: false,
+        });
+    }
+
+    pub fn mimetypes(mut self, mimetypes: MimeTypes) -> StaticFiles {
+        self.mimetypes = mimetypes;
+        return self;
+    }
+
+    pub fn autoindex(mut self, autoindex: bool) -> StaticFiles {
+        self.autoindex = autoindex;
+        return self;
+    }
+
+    pub fn showhidden(mut self, showhidden: bool) -> StaticFiles {
+        self.showhidden = showhidden;
+        return self;
+    }
+
+    pub fn weaketags(mut self, weaketags: bool) -> StaticFiles {
+        self.weaketags = weaketags;
+        return self;
+    }
+
+    pub fn precompressed(mut self, precompressed: bool) -> StaticFiles {
+        self.precompressed = precompressed;
+        return self;
+    }
+}
+
+impl Handler for StaticFiles {
+    fn call(&self, request: &HttpRequest) -> HttpResponse {
+        return handle_static_request(self, request);
+    }
+}
+
+fn get_path_response(sessionid: &Uuid, staticfiles: &StaticFiles, httprequest: &HttpRequest) -> HttpResponse {
+    info!("{},Getting path response for {}", sessionid, &httprequest.path);
+    let path = match staticfiles.pathresolver.resolve(&httprequest.path) {
+        Ok(path) => path,
+        Err(e) => return get_path_error_response(sessionid, &httprequest.path, e),
+    };
+
+    if path.is_dir() { return get_directory_response(sessionid, staticfiles, httprequest); }
+
+    return get_file_response(sessionid, staticfiles, httprequest, &path);
+}
+
+//  Serves the file, or just its validators when the request's preconditions say it should not be sent
+fn get_file_response(sessionid: &Uuid, staticfiles: &StaticFiles, httprequest: &HttpRequest, path: &Path) -> HttpResponse {
+    let (servedpath, coding, hasvariants) = get_precompressed_variant(staticfiles, httprequest, path);
+
+    let (file, metadata) = match open_file(sessionid, &servedpath) {
+        Some(file) => file,
+        None => return create_empty_response(sessionid, HttpStatusCode::NotFound),
+    };
+
+    //  Each variant has its own size and modification time, so its own validators
+    let validators = Validators::from_metadata(&metadata, staticfiles.weaketags);
+    let contenttype = staticfiles.mimetypes.get_content_type(path);
+
+    let mut response = match evaluate_preconditions(httprequest, &validators) {
+        Precondition::Proceed => get_file_content_response(sessionid, httprequest, &servedpath, &contenttype, file, &metadata, &validators),
+        Precondition::NotModified => create_empty_response(sessionid, HttpStatusCode::NotModified),
+        Precondition::Failed => return create_empty_response(sessionid, HttpStatusCode::PreconditionFailed),
+    };
+
+    //  A 304 only carries the ETag and Vary, the rest of the 200's headers describe a body it does not have
+    let notmodified = matches!(response.head.status, HttpStatusCode::NotModified);
+
+    response.head.headers.insert("ETag".to_string(), validators.etag.clone());
+    if let Some(lastmodified) = validators.get_last_modified().filter(|_| !notmodified) {
+        response.head.headers.insert("Last-Modified".to_string(), lastmodified);
+    }
+
+    match coding {
+        Some(coding) => {
+            info!("{},Serving precompressed {}", sessionid, servedpath.display());
+            if !notmodified { response.head.headers.insert("Content-Encoding".to_string(), coding.get_name().to_string()); }
+        },
+        //  Lets the compression middleware decide a 304 or 206 the way it would the file's 200
+        None => response.head.representation = Some(Representation { contenttype, length: metadata.len() }),
+    }
+    if hasvariants { compression::add_vary(&mut response, "Accept-Encoding"); }
+
+    return response;
+}
+
+//  Finds the precompressed sibling of the file best matching Accept-Encoding
+//  Returns the path to serve, the coding it is in and whether any sibling exists at all
+fn get_precompressed_variant(staticfiles: &StaticFiles, httprequest: &HttpRequest, path: &Path) -> (PathBuf, Option<ContentCoding>, bool) {
+    if !staticfiles.precompressed { return (path.to_path_buf(), None, false); }
+
+    let variants = ContentCoding::PRECOMPRESSED.iter()
+        .filter_map(|coding| {
+            let sibling = staticfiles.pathresolver.resolve_sibling(path, coding.get_file_extension()).ok()?;
+            return if sibling.is_file() { Some((*coding, sibling)) } else { None };
+        })
+        .collect::<Vec<(ContentCoding, PathBuf)>>();
+
+    let codings = variants.iter().map(|(coding, _)| *coding).collect::<Vec<ContentCoding>>();
+
+    return match compression::negotiate_encoding(httprequest.headers.get("accept-encoding"), &codings) {
+        Some(coding) => {
+            let sibling = variants.into_iter().find(|(variant, _)| *variant == coding).map(|(_, sibling)| sibling).unwrap_or(path.to_path_buf());
+            (sibling, Some(coding), true)
+        },
+        None => (path.to_path_buf(), None, !variants.is_empty()),
+    };
+}
+
+//  Sends the whole file, or the byte ranges the request asked for
+fn get_file_content_response(sessionid: &Uuid, httprequest: &HttpRequest, path: &Path, contenttype: &str, file: File, metadata: &Metadata, validators: &Validators) -> HttpResponse {
+    let contenttype = contenttype.to_string();
+    let filelength = metadata.len();
+
+    let body = match ranges::get_range_request(httprequest, validators, filelength) {
+        RangeRequest::Full => Ok((HttpStatusCode::Ok, contenttype, HttpBody::stream(file, Some(filelength)), None)),
+        RangeRequest::Partial(byteranges) if byteranges.len() == 1 => {
+            let range = byteranges[0];
+            ranges::open_range(file, &range)
+                .map(|reader| (HttpStatusCode::PartialContent, contenttype, HttpBody::stream(reader, Some(range.length())), Some(range.get_content_range(filelength))))
+        },
+        RangeRequest::Partial(byteranges) => {
+            let boundary = Uuid::new_v4().simple().to_string();
+            ranges::open_multipart_ranges(path, &byteranges, &contenttype, filelength, &boundary)
+                .map(|(reader, length)| (HttpStatusCode::PartialContent, format!("multipart/byteranges; boundary={}", boundary), HttpBody::stream(reader, Some(length)), None))
+        },
+        RangeRequest::Unsatisfiable => Ok((HttpStatusCode::RangeNotSatisfiable, String::new(), HttpBody::Empty, Some(format!("bytes */{}", filelength)))),
+    };
+
+    let mut response = match body {
+        Ok((status, contenttype, body, contentrange)) => {
+            let mut response = create_response(sessionid, status, &contenttype, body);
+            if let Some(contentrange) = contentrange {
+                response.head.headers.insert("Content-Range".to_string(), contentrange);
+            }
+            response
+        },
+        Err(e) => {
+            error!("{},Could not read ranges of {}: {}", sessionid, path.display(), e);
+            create_empty_response(sessionid, HttpStatusCode::InternalServerError)
+        },
+    };
+    response.head.headers.insert("Accept-Ranges".to_string(), "bytes".to_string());
+
+    return response;
+}
+
+//  Serves the index.html of a directory, or a listing of it when there is no index and autoindex is on
+fn get_directory_response(sessionid: &Uuid, staticfiles: &StaticFiles, httprequest: &HttpRequest) -> HttpResponse {
+    //  Relative links in the index or the listing only work when the path ends with a slash
+    if !httprequest.path.ends_with('/') {
+        //  Built from the normalized path rather than the raw one, which could start with // and leave the site
+        let mut location = match normalize_path(&httprequest.path) {
+            Ok(path) if path.ends_with('/') => path,
+            Ok(path) => format!("{}/", path),
+            Err(e) => return get_path_error_response(sessionid, &httprequest.path, e),
+        };
+        let mut response = create_empty_response(sessionid, HttpStatusCode::MovedPermanently);
+        if !httprequest.query.is_empty() { location = format!("{}?{}", location, &httprequest.query); }
+        response.head.headers.insert("Location".to_string(), location);
+        return response;
+    }
+
+    match staticfiles.pathresolver.resolve(&format!("{}{}", &httprequest.path, DIRECTORYINDEX)) {
+        Ok(indexpath) => return get_file_response(sessionid, staticfiles, httprequest, &indexpath),
+        Err(PathError::NotFound) => {},
+        Err(e) => return get_path_error_response(sessionid, &httprequest.path, e),
+    }
+
+    if !staticfiles.autoindex { return create_empty_response(sessionid, HttpStatusCode::NotFound); }
+
+    return get_listing_response(sessionid, staticfiles, httprequest);
+}
+
+//  Lists the directory as HTML, or as JSON when the client accepts it
+//  Entries the client would be refused, such as symlinks the policy denies, are left out
+fn get_listing_response(sessionid: &Uuid, staticfiles: &StaticFiles, httprequest: &HttpRequest) -> HttpResponse {
+    let path = match staticfiles.pathresolver.resolve(&httprequest.path) {
+        Ok(path) => path,
+        Err(e) => return get_path_error_response(sessionid, &httprequest.path, e),
+    };
+
+    let mut entries = match directorylisting::read_entries(&path, staticfiles.showhidden) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("{},Could not list {}: {}", sessionid, path.display(), e);
+            return create_empty_response(sessionid, HttpStatusCode::InternalServerError);
+        },
+    };
+    entries.retain(|entry| staticfiles.pathresolver.resolve(&format!("{}{}", &httprequest.path, percent_encode_segment(&entry.name))).is_ok());
+
+    let sortkey = httprequest.get_query_parameter("sort").and_then(|sort| sort.parse::<SortKey>().ok()).unwrap_or(SortKey::Name);
+    let descending = httprequest.get_query_parameter("order").is_some_and(|order| order == "desc");
+    directorylisting::sort_entries(&mut entries, &sortkey, descending);
+
+    let acceptsjson = httprequest.headers.get("accept").is_some_and(|accept| accept.contains("application/json"));
+    let displaypath = String::from_utf8_lossy(&percent_decode(&httprequest.path).unwrap_or_default()).to_string();
+
+    let mut response = match acceptsjson {
+        true => create_response(sessionid, HttpStatusCode::Ok, JSONCONTENTTYPE, directorylisting::render_json(&displaypath, &entries)),
+        false => create_response(sessionid, HttpStatusCode::Ok, HTMLCONTENTTYPE, directorylisting::render_html(&displaypath, &entries, &sortkey, descending)),
+    };
+    response.head.headers.insert("Vary".to_string(), "Accept".to_string());
+
+    return response;
+}
+
+fn get_path_error_response(sessionid: &Uuid, requestpath: &str, error: PathError) -> HttpResponse {
+    warn!("{},Refusing path {}: {}", sessionid, requestpath, error);
+    return create_empty_response(sessionid, error.get_status_code());
+}
+
+//  Answers GET and HEAD from the files, and OPTIONS with the methods they support
+fn handle_static_request(staticfiles: &StaticFiles, httprequest: &HttpRequest) -> HttpResponse {
+    let sessionid = &httprequest.sessionid;
+
+    match httprequest.method {
+        HttpMethod::GET | HttpMethod::HEAD => return get_path_response(sessionid, staticfiles, httprequest),
+        HttpMethod::OPTIONS => {
+            let mut response = create_empty_response(sessionid, HttpStatusCode::NoContent);
+            response.head.headers.insert("Allow".to_string(), ALLOWEDMETHODS.to_string());
+            return response;
+        },
+        _ => {
+            let mut response = create_empty_response(sessionid, HttpStatusCode::MethodNotAllowed);
+            response.head.headers.insert("Allow".to_string(), ALLOWEDMETHODS.to_string());
+            return response;
+        },
+    }
+}
+
+//  Opens the file for streaming and gets its metadata
+fn open_file(sessionid: &Uuid, path: &Path) -> Option<(File, Metadata)> {
+    info!("{},Looking for file:{}", sessionid, path.display());
+
+    let file = File::open(path).ok()?;
+    let metadata = file.metadata().ok()?;
+
+    if !metadata.is_file() { return None; }
+
+    return Some((file, metadata));
+}
@@ -1,84 +1,591 @@
-use log::trace;
+use log::{error, trace};
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
     thread,
+    time::Duration,
 };
 
 #[derive(Debug)]
 pub enum PoolCreationError {
     InvalidSize,
+    InvalidCapacity,
 }
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+//  What the pool does with new work when its queue is full
+#[derive(Clone, Copy)]
+pub enum OverloadPolicy {
+    //  Wait for a worker to take a job off the queue, which stops the server accepting meanwhile
+    //  Callers that must never wait, such as the event loops, shed the job as Reject does
+    Block,
+    //  Shed the new job
+    Reject,
+    //  Shed the job that has waited longest to make room for the new one
+    DropOldest,
+}
+
+impl Default for OverloadPolicy {
+    fn default() -> OverloadPolicy {
+        return OverloadPolicy::Block;
+    }
+}
+
+impl FromStr for OverloadPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "block" => Ok(Self::Block),
+            "reject" => Ok(Self::Reject),
+            "dropoldest" => Ok(Self::DropOldest),
+            _ => Err("Invalid overload policy".to_string()),
+        }
+    }
+}
 
+//  Work for the pool, with a way to turn it away cheaply when the pool is overloaded
+pub trait Job: Send {
+    fn run(self: Box<Self>);
+    fn shed(self: Box<Self>);
+}
+
+//  Jobs waiting for a worker, and the workers' counts, kept under one lock so they agree with each other
+struct Queue {
+    jobs: VecDeque<Box<dyn Job>>,
+    closed: bool,
+    //  Workers running, whether busy or idle
+    workers: usize,
+    //  Workers waiting for a job
+    idleworkers: usize,
+    peakworkers: usize,
+    completed: usize,
+    spawned: usize,
+    retired: usize,
+    panicked: usize,
+}
+
+//  A snapshot of what the pool is doing
+#[derive(Clone, Copy, Debug)]
+pub struct PoolStats {
+    pub workers: usize,
+    pub idleworkers: usize,
+    pub busyworkers: usize,
+    pub queued: usize,
+    pub peakworkers: usize,
+    pub completed: usize,
+    pub spawned: usize,
+    pub retired: usize,
+    pub panicked: usize,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    //  Signalled when a job is queued or the pool closes
+    notempty: Condvar,
+    //  Signalled when a worker takes a job off the queue
+    notfull: Condvar,
+    capacity: usize,
+    minworkers: usize,
+    maxworkers: usize,
+    //  How long a worker above the minimum waits for a job before it retires
+    idletimeout: Duration,
+    //  Threads of the running workers, joined when the pool is dropped
+    threads: Mutex<Vec<Worker>>,
+    nextworkerid: AtomicUsize,
+}
+
+impl Shared {
+    //  Jobs run outside the lock, so a poisoned lock still guards a consistent queue and is used as it is
+    fn lock_queue(&self) -> MutexGuard<'_, Queue> {
+        return self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+    }
+
+    fn lock_threads(&self) -> MutexGuard<'_, Vec<Worker>> {
+        return self.threads.lock().unwrap_or_else(PoisonError::into_inner);
+    }
+
+    fn get_stats(&self) -> PoolStats {
+        let queue = self.lock_queue();
+
+        return PoolStats {
+            workers: queue.workers,
+            idleworkers: queue.idleworkers,
+            busyworkers: queue.workers - queue.idleworkers,
+            queued: queue.jobs.len(),
+            peakworkers: queue.peakworkers,
+            completed: queue.completed,
+            spawned: queue.spawned,
+            retired: queue.retired,
+            panicked: queue.panicked,
+        };
+    }
+}
+
+//  Reads the stats of a pool from another thread, while the pool itself is owned by the server
+#[derive(Clone)]
+pub struct PoolMonitor {
+    shared: Arc<Shared>,
+}
+
+impl PoolMonitor {
+    pub fn stats(&self) -> PoolStats {
+        return self.shared.get_stats();
+    }
+}
+
+//  Runs jobs on between minworkers and maxworkers threads
+//  Workers are added while jobs are waiting with none idle, and retire again once they have been idle for the idle timeout
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    shared: Arc<Shared>,
+    policy: OverloadPolicy,
 }
 
 impl ThreadPool {
-    pub fn new(size: usize) -> Result<ThreadPool, PoolCreationError> {
-
-        if size <= 0 { return Err(PoolCreationError::InvalidSize); }
+    pub fn new(minworkers: usize, maxworkers: usize, idletimeout: Duration, capacity: usize, policy: OverloadPolicy) -> Result<ThreadPool, PoolCreationError> {
 
-        let mut workers = Vec::with_capacity(size);
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        if maxworkers == 0 || minworkers > maxworkers { return Err(PoolCreationError::InvalidSize); }
+        if capacity == 0 { return Err(PoolCreationError::InvalidCapacity); }
 
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::with_capacity(capacity),
+                closed: false,
+                workers: 0,
+                idleworkers: 0,
+                peakworkers: 0,
+                completed: 0,
+                spawned: 0,
+                retired: 0,
+                panicked: 0,
+            }),
+            notempty: Condvar::new(),
+            notfull: Condvar::new(),
+            capacity,
+            minworkers,
+            maxworkers,
+            idletimeout,
+            threads: Mutex::new(Vec::with_capacity(maxworkers)),
+            nextworkerid: AtomicUsize::new(0),
+        });
 
-        for id in 0..size {
-            trace!("Creating worker {}", id);
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        {
+            let mut queue = shared.lock_queue();
+            for _ in 0..minworkers { Worker::spawn(&shared, &mut queue); }
         }
 
-        return Ok(ThreadPool { workers, sender: Some(sender) });
+        return Ok(ThreadPool { shared, policy });
+    }
+
+    pub fn monitor(&self) -> PoolMonitor {
+        return PoolMonitor { shared: Arc::clone(&self.shared) };
+    }
+
+    //  Queues the job, applying the overload policy when the queue is full
+    //  A shed job is shed on the calling thread once the queue lock is released
+    pub fn execute(&self, job: impl Job + 'static) {
+        self.submit(Box::new(job), self.policy);
     }
 
-    pub fn execute<F>(&self, f: F)
-    where F: FnOnce() + Send + 'static, {
-        let job = Box::new(f);
-        self.sender.as_ref().unwrap().send(job).unwrap();
+    //  Queues the job like execute, but sheds it rather than wait when the policy is to block
+    pub fn try_execute(&self, job: impl Job + 'static) {
+        let policy = match self.policy {
+            OverloadPolicy::Block => OverloadPolicy::Reject,
+            policy => policy,
+        };
+
+        self.submit(Box::new(job), policy);
+    }
+
+    fn submit(&self, job: Box<dyn Job>, policy: OverloadPolicy) {
+        let mut queue = self.shared.lock_queue();
+
+        //  Grow the pool before resorting to the overload policy
+        if queue.jobs.len() >= queue.idleworkers && queue.workers < self.shared.maxworkers {
+            Worker::spawn(&self.shared, &mut queue);
+        }
+
+        let shed = if queue.jobs.len() < self.shared.capacity {
+            queue.jobs.push_back(job);
+            None
+        } else {
+            match policy {
+                OverloadPolicy::Block => {
+                    while queue.jobs.len() >= self.shared.capacity {
+                        queue = self.shared.notfull.wait(queue).unwrap_or_else(PoisonError::into_inner);
+                    }
+                    queue.jobs.push_back(job);
+                    None
+                },
+                OverloadPolicy::Reject => Some(job),
+                OverloadPolicy::DropOldest => {
+                    let oldest = queue.jobs.pop_front();
+                    queue.jobs.push_back(job);
+                    oldest
+                },
+            }
+        };
+
+        drop(queue);
+        self.shared.notempty.notify_one();
+
+        if let Some(job) = shed {
+            trace!("Queue full, shedding a job");
+            job.shed();
+        }
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
+        self.shared.lock_queue().closed = true;
+        self.shared.notempty.notify_all();
+
+        //  A worker that panics adds its replacement before it exits, so keep going until none are left
+        loop {
+            let worker = match self.shared.lock_threads().pop() {
+                Some(worker) => worker,
+                None => break,
+            };
 
-        for worker in &mut self.workers {
             trace!("Shutting down worker {}", worker.id);
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            }
+            if worker.thread.join().is_err() { error!("Worker {} ended with a panic", worker.id); }
         }
     }
 }
 
 struct Worker {
     id: usize,
-    thread: Option<thread::JoinHandle<()>>,
+    thread: thread::JoinHandle<()>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        trace!("Worker {} created", id);
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
+    //  Starts a worker taking jobs off the queue, the caller holds the queue lock so the counts stay right
+    fn spawn(shared: &Arc<Shared>, queue: &mut Queue) {
+        let id = shared.nextworkerid.fetch_add(1, Ordering::SeqCst);
+        let workershared = Arc::clone(shared);
+
+        match thread::Builder::new().name(format!("worker-{}", id)).spawn(move || Worker::run(id, workershared)) {
+            Ok(thread) => {
+                trace!("Worker {} created", id);
+                queue.workers += 1;
+                queue.spawned += 1;
+                queue.peakworkers = queue.peakworkers.max(queue.workers);
+                shared.lock_threads().push(Worker { id, thread });
+            },
+            Err(e) => error!("Could not start worker {}: {}", id, e),
+        }
+    }
+
+    fn run(id: usize, shared: Arc<Shared>) {
+        loop {
+            let message = {
+                let mut queue = shared.lock_queue();
+                queue.idleworkers += 1;
+
+                while queue.jobs.is_empty() && !queue.closed {
+                    let (guard, timeout) = shared.notempty.wait_timeout(queue, shared.idletimeout).unwrap_or_else(PoisonError::into_inner);
+                    queue = guard;
+
+                    if timeout.timed_out() && queue.jobs.is_empty() && queue.workers > shared.minworkers {
+                        trace!("Worker {} idle for {}s, retiring", id, shared.idletimeout.as_secs());
+                        queue.idleworkers -= 1;
+                        queue.workers -= 1;
+                        queue.retired += 1;
+                        drop(queue);
+                        Worker::detach(id, &shared);
+                        return;
+                    }
+                }
+
+                queue.idleworkers -= 1;
+                let job = queue.jobs.pop_front();
+                if job.is_none() { queue.workers -= 1; }
+                job
+            };
 
             match message {
-                Ok(job) => {
+                Some(job) => {
+                    shared.notfull.notify_one();
                     trace!("Worker {} got a job.", id);
-                    job();
+                    //  A panicking job must not take the worker down with it and shrink the pool
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| job.run()));
+                    let mut queue = shared.lock_queue();
+                    queue.completed += 1;
+
+                    if let Err(panic) = result {
+                        error!("Worker {} panicked running a job: {}, replacing it", id, get_panic_message(&panic));
+                        queue.panicked += 1;
+                        queue.workers -= 1;
+                        Worker::spawn(&shared, &mut queue);
+                        drop(queue);
+                        Worker::detach(id, &shared);
+                        return;
+                    }
                     trace!("Worker {} finished a job", id);
                 },
-                Err(_) => {
+                None => {
                     trace!("Worker {} shutting down", id);
                     break;
 
                 }
             }
-        });
+        }
+    }
+
+    //  Forgets the thread of a worker that is exiting on its own, so retired workers do not pile up
+    fn detach(id: usize, shared: &Shared) {
+        shared.lock_threads().retain(|worker| worker.id != id);
+    }
+}
+
+//  Gets the message a panic was raised with, panics carry either a &str or a String
+pub fn get_panic_message(panic: &Box<dyn Any + Send>) -> String {
+    return match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => panic.downcast_ref::<String>().cloned().unwrap_or("unknown panic".to_string()),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc::{self, Receiver, Sender},
+        time::Instant,
+    };
+
+    use super::*;
+
+    //  How long a test waits for something the pool should do promptly
+    const TIMEOUT: Duration = Duration::from_secs(5);
 
-        return Worker { id, thread: Some(thread) };
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Started(usize),
+        Finished(usize),
+        Shed(usize),
     }
-}
\ No newline at end of file
+
+    //  Holds jobs running until it is opened
+    #[derive(Clone)]
+    struct Gate {
+        open: Arc<(Mutex<bool>, Condvar)>,
+    }
+
+    impl Gate {
+        fn new() -> Gate {
+            return Gate { open: Arc::new((Mutex::new(false), Condvar::new())) };
+        }
+
+        fn open(&self) {
+            *self.open.0.lock().unwrap() = true;
+            self.open.1.notify_all();
+        }
+
+        fn wait(&self) {
+            let mut open = self.open.0.lock().unwrap();
+            while !*open { open = self.open.1.wait(open).unwrap(); }
+        }
+    }
+
+    //  Reports what the pool did with it, and waits at the gate while running
+    struct TestJob {
+        id: usize,
+        gate: Gate,
+        events: Sender<Event>,
+    }
+
+    impl Job for TestJob {
+        fn run(self: Box<Self>) {
+            let _ = self.events.send(Event::Started(self.id));
+            self.gate.wait();
+            let _ = self.events.send(Event::Finished(self.id));
+        }
+
+        fn shed(self: Box<Self>) {
+            let _ = self.events.send(Event::Shed(self.id));
+        }
+    }
+
+    //  Panics when run, as a bug in a handler would
+    struct PanickingJob;
+
+    impl Job for PanickingJob {
+        fn run(self: Box<Self>) {
+            panic!("handler bug");
+        }
+
+        fn shed(self: Box<Self>) {}
+    }
+
+    fn create_pool(minworkers: usize, maxworkers: usize, capacity: usize, policy: OverloadPolicy) -> ThreadPool {
+        return ThreadPool::new(minworkers, maxworkers, Duration::from_secs(60), capacity, policy).unwrap();
+    }
+
+    fn create_job(id: usize, gate: &Gate, events: &Sender<Event>) -> TestJob {
+        return TestJob { id, gate: gate.clone(), events: events.clone() };
+    }
+
+    fn next_event(events: &Receiver<Event>) -> Event {
+        return events.recv_timeout(TIMEOUT).expect("The pool did nothing with the job");
+    }
+
+    //  Collects events until the pool has settled, sorted so the order workers happened to run in does not matter
+    fn drain_events(events: &Receiver<Event>, count: usize) -> Vec<String> {
+        let mut drained = (0..count).map(|_| format!("{:?}", next_event(events))).collect::<Vec<String>>();
+        drained.sort();
+        return drained;
+    }
+
+    //  Waits for the stats to satisfy the condition, returning the last seen either way
+    fn wait_for_stats(pool: &ThreadPool, condition: impl Fn(&PoolStats) -> bool) -> PoolStats {
+        let started = Instant::now();
+        loop {
+            let stats = pool.monitor().stats();
+            if condition(&stats) || started.elapsed() > TIMEOUT { return stats; }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_sizes() {
+        assert!(matches!(ThreadPool::new(1, 0, Duration::ZERO, 1, OverloadPolicy::Block), Err(PoolCreationError::InvalidSize)));
+        assert!(matches!(ThreadPool::new(3, 2, Duration::ZERO, 1, OverloadPolicy::Block), Err(PoolCreationError::InvalidSize)));
+        assert!(matches!(ThreadPool::new(1, 1, Duration::ZERO, 0, OverloadPolicy::Block), Err(PoolCreationError::InvalidCapacity)));
+    }
+
+    #[test]
+    fn parses_overload_policies() {
+        assert!(matches!("dropoldest".parse::<OverloadPolicy>(), Ok(OverloadPolicy::DropOldest)));
+        assert!("drop".parse::<OverloadPolicy>().is_err());
+    }
+
+    #[test]
+    fn reject_sheds_the_new_job_when_full() {
+        let pool = create_pool(1, 1, 1, OverloadPolicy::Reject);
+        let (sender, events) = mpsc::channel();
+        let gate = Gate::new();
+
+        pool.execute(create_job(0, &gate, &sender));
+        assert_eq!(next_event(&events), Event::Started(0));
+        pool.execute(create_job(1, &gate, &sender));
+        pool.execute(create_job(2, &gate, &sender));
+        assert_eq!(next_event(&events), Event::Shed(2));
+        assert_eq!(pool.monitor().stats().queued, 1);
+
+        gate.open();
+        assert_eq!(drain_events(&events, 3), vec!["Finished(0)", "Finished(1)", "Started(1)"]);
+    }
+
+    #[test]
+    fn dropoldest_sheds_the_longest_waiting_job_when_full() {
+        let pool = create_pool(1, 1, 2, OverloadPolicy::DropOldest);
+        let (sender, events) = mpsc::channel();
+        let gate = Gate::new();
+
+        pool.execute(create_job(0, &gate, &sender));
+        assert_eq!(next_event(&events), Event::Started(0));
+        for id in 1..4 { pool.execute(create_job(id, &gate, &sender)); }
+        assert_eq!(next_event(&events), Event::Shed(1));
+
+        gate.open();
+        assert_eq!(drain_events(&events, 5), vec!["Finished(0)", "Finished(2)", "Finished(3)", "Started(2)", "Started(3)"]);
+    }
+
+    #[test]
+    fn block_waits_for_room_in_the_queue() {
+        let pool = Arc::new(create_pool(1, 1, 1, OverloadPolicy::Block));
+        let (sender, events) = mpsc::channel();
+        let gate = Gate::new();
+
+        pool.execute(create_job(0, &gate, &sender));
+        assert_eq!(next_event(&events), Event::Started(0));
+        pool.execute(create_job(1, &gate, &sender));
+
+        let (returned, submitted) = mpsc::channel();
+        let submitter = {
+            let (pool, gate, sender) = (pool.clone(), gate.clone(), sender.clone());
+            thread::spawn(move || {
+                pool.execute(create_job(2, &gate, &sender));
+                returned.send(()).unwrap();
+            })
+        };
+
+        assert!(submitted.recv_timeout(Duration::from_millis(200)).is_err());
+        gate.open();
+        submitted.recv_timeout(TIMEOUT).expect("execute never returned");
+        submitter.join().unwrap();
+
+        assert_eq!(drain_events(&events, 5), vec!["Finished(0)", "Finished(1)", "Finished(2)", "Started(1)", "Started(2)"]);
+    }
+
+    #[test]
+    fn try_execute_sheds_rather_than_blocks() {
+        let pool = create_pool(1, 1, 1, OverloadPolicy::Block);
+        let (sender, events) = mpsc::channel();
+        let gate = Gate::new();
+
+        pool.execute(create_job(0, &gate, &sender));
+        assert_eq!(next_event(&events), Event::Started(0));
+        pool.try_execute(create_job(1, &gate, &sender));
+        pool.try_execute(create_job(2, &gate, &sender));
+        assert_eq!(next_event(&events), Event::Shed(2));
+
+        gate.open();
+        assert_eq!(drain_events(&events, 3), vec!["Finished(0)", "Finished(1)", "Started(1)"]);
+    }
+
+    #[test]
+    fn replaces_a_worker_whose_job_panicked() {
+        let pool = create_pool(1, 1, 4, OverloadPolicy::Block);
+        let (sender, events) = mpsc::channel();
+        let gate = Gate::new();
+        gate.open();
+
+        pool.execute(PanickingJob);
+        pool.execute(create_job(1, &gate, &sender));
+        assert_eq!(drain_events(&events, 2), vec!["Finished(1)", "Started(1)"]);
+
+        let stats = wait_for_stats(&pool, |stats| stats.completed == 2);
+        assert_eq!((stats.panicked, stats.spawned, stats.workers), (1, 2, 1));
+
+        //  The replacement keeps serving
+        pool.execute(create_job(2, &gate, &sender));
+        assert_eq!(drain_events(&events, 2), vec!["Finished(2)", "Started(2)"]);
+    }
+
+    #[test]
+    fn gets_panic_messages() {
+        let message = |payload: fn()| get_panic_message(&panic::catch_unwind(payload).unwrap_err());
+
+        assert_eq!(message(|| panic!("static")), "static");
+        assert_eq!(message(|| panic!("formatted {}", 1)), "formatted 1");
+        assert_eq!(message(|| panic::panic_any(1)), "unknown panic");
+    }
+
+    #[test]
+    fn grows_to_maxworkers_under_load_and_retires_idle_workers() {
+        let pool = ThreadPool::new(1, 3, Duration::from_millis(100), 8, OverloadPolicy::Block).unwrap();
+        let (sender, events) = mpsc::channel();
+        let gate = Gate::new();
+
+        for id in 0..5 { pool.execute(create_job(id, &gate, &sender)); }
+        for _ in 0..3 { assert!(matches!(next_event(&events), Event::Started(_))); }
+        assert!(events.recv_timeout(Duration::from_millis(200)).is_err());
+
+        let stats = pool.monitor().stats();
+        assert_eq!((stats.workers, stats.busyworkers, stats.queued), (3, 3, 2));
+
+        gate.open();
+        assert_eq!(drain_events(&events, 7).iter().filter(|event| event.starts_with("Finished")).count(), 5);
+
+        //  Back down to minworkers once the extra workers have been idle for the idle timeout
+        let stats = wait_for_stats(&pool, |stats| stats.workers == 1);
+        assert_eq!((stats.workers, stats.peakworkers, stats.spawned, stats.retired), (1, 3, 3, 2));
+    }
+}
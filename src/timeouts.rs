@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+
+use crate::{config::ServerConfig, http::{get_head_length, ReadError}};
+
+//  How long the body of a request may take before the minimum rate applies
+const BODYGRACEPERIOD: Duration = Duration::from_secs(10);
+
+//  Which limit a request ran out of time against
+#[derive(Clone, Copy)]
+pub(crate) enum Expiry {
+    //  No request was started before the keep-alive timeout
+    Idle,
+    //  The request head did not arrive in time
+    Head,
+    //  The body arrived slower than the minimum rate
+    Body,
+}
+
+impl Expiry {
+    pub fn get_read_error(&self, config: &ServerConfig) -> ReadError {
+        return match self {
+            Self::Idle => ReadError::IdleTimeout,
+            Self::Head => ReadError::RequestTimeout(format!("Request head not received within {}s", config.headertimeout.as_secs())),
+            Self::Body => ReadError::RequestTimeout(format!("Request body received slower than {} bytes a second", config.minbodyrate)),
+        };
+    }
+}
+
+//  Tracks how far the request being read has got, to know when it has taken too long
+//  A fresh connection has the header timeout to send its first request, a kept alive one
+//  the keep-alive timeout to start the next, and then the header timeout from its first byte
+pub(crate) struct RequestTimer {
+    waitstart: Instant,
+    firstrequest: bool,
+    headstart: Option<Instant>,
+    bodystart: Option<Instant>,
+}
+
+impl RequestTimer {
+    pub fn new(firstrequest: bool) -> RequestTimer {
+        return RequestTimer { waitstart: Instant::now(), firstrequest, headstart: None, bodystart: None };
+    }
+
+    //  Notes what has been received of the request so far, and returns when it times out and against which limit
+    //  Returns None when nothing limits how long the request may take
+    pub fn get_deadline(&mut self, config: &ServerConfig, buffered: &[u8]) -> Option<(Instant, Expiry)> {
+        if buffered.is_empty() && !self.firstrequest {
+            return Some((self.waitstart + config.keepalivetimeout, Expiry::Idle));
+        }
+
+        let headlength = match get_head_length(buffered) {
+            Some(headlength) => headlength,
+            None => {
+                let headstart = if self.firstrequest { self.waitstart } else { *self.headstart.get_or_insert_with(Instant::now) };
+                return Some((headstart + config.headertimeout, Expiry::Head));
+            },
+        };
+
+        if config.minbodyrate == 0 { return None; }
+
+        //  Every minbodyrate bytes received buys the body another second
+        let bodystart = *self.bodystart.get_or_insert_with(Instant::now);
+        let received = (buffered.len() - headlength) as f64;
+        let earned = Duration::from_secs_f64(received / config.minbodyrate as f64);
+
+        return Some((bodystart + BODYGRACEPERIOD + earned, Expiry::Body));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::RequestLimits;
+
+    const HEAD: &[u8] = b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100\r\n\r\n";
+
+    fn create_config(minbodyrate: u64) -> ServerConfig {
+        return ServerConfig {
+            keepalivetimeout: Duration::from_secs(5),
+            headertimeout: Duration::from_secs(10),
+            minbodyrate,
+            writetimeout: Duration::from_secs(10),
+            limits: RequestLimits::default(),
+            keepalivemaxrequests: 100,
+            servername: None,
+            graceperiod: Duration::from_secs(1),
+            retryafter: Duration::from_secs(1),
+        };
+    }
+
+    #[test]
+    fn gives_a_fresh_connection_the_header_timeout_from_connecting() {
+        let config = create_config(0);
+        let mut timer = RequestTimer::new(true);
+
+        assert!(matches!(timer.get_deadline(&config, b""), Some((deadline, Expiry::Head)) if deadline == timer.waitstart + config.headertimeout));
+        assert!(matches!(timer.get_deadline(&config, b"GET / HT"), Some((deadline, Expiry::Head)) if deadline == timer.waitstart + config.headertimeout));
+    }
+
+    #[test]
+    fn gives_a_kept_alive_connection_the_keepalive_timeout_then_the_header_timeout() {
+        let config = create_config(0);
+        let mut timer = RequestTimer::new(false);
+
+        assert!(matches!(timer.get_deadline(&config, b""), Some((deadline, Expiry::Idle)) if deadline == timer.waitstart + config.keepalivetimeout));
+
+        //  The header timeout runs from the first byte, and later reads do not restart it
+        let headdeadline = match timer.get_deadline(&config, b"GET") {
+            Some((deadline, Expiry::Head)) => deadline,
+            _ => panic!("Expected the header timeout"),
+        };
+        assert_eq!(headdeadline, timer.headstart.unwrap() + config.headertimeout);
+        assert!(matches!(timer.get_deadline(&config, b"GET / HT"), Some((deadline, Expiry::Head)) if deadline == headdeadline));
+    }
+
+    #[test]
+    fn limits_bodies_by_rate_only_when_asked() {
+        let mut timer = RequestTimer::new(true);
+        assert!(timer.get_deadline(&create_config(0), HEAD).is_none());
+
+        let config = create_config(10);
+        let mut timer = RequestTimer::new(true);
+        let started = match timer.get_deadline(&config, HEAD) {
+            Some((deadline, Expiry::Body)) => deadline,
+            _ => panic!("Expected the body rate limit"),
+        };
+        assert_eq!(started, timer.bodystart.unwrap() + BODYGRACEPERIOD);
+
+        //  Every minbodyrate bytes received buys another second
+        let received = [HEAD, &[b'x'; 25]].concat();
+        assert!(matches!(timer.get_deadline(&config, &received), Some((deadline, Expiry::Body)) if deadline == started + Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn maps_expiries_onto_read_errors() {
+        let config = create_config(10);
+
+        assert!(matches!(Expiry::Idle.get_read_error(&config), ReadError::IdleTimeout));
+        assert!(matches!(Expiry::Head.get_read_error(&config), ReadError::RequestTimeout(_)));
+        assert!(matches!(Expiry::Body.get_read_error(&config), ReadError::RequestTimeout(_)));
+    }
+}
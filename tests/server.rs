@@ -0,0 +1,338 @@
+#![allow(clippy::needless_return)]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use simple_http_server::{
+    http::{HttpRequest, HttpResponse, HttpStatusCode, RequestLimits},
+    pathresolver::SymlinkPolicy,
+    responses::create_response,
+    IoMode, OverloadPolicy, PathParams, Router, Server, StaticFiles,
+};
+
+//  How long a test waits on the server before deciding it is stuck
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+//  A response as the client sees it
+struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        return self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str());
+    }
+}
+
+fn get_greeting(request: &HttpRequest, params: &PathParams) -> HttpResponse {
+    let name = params.get::<String>("name").unwrap_or_default();
+    return create_response(&request.sessionid, HttpStatusCode::Ok, "text/plain", format!("hello {} from {}", name, request.method));
+}
+
+//  A root of its own holding a single file
+fn create_root() -> PathBuf {
+    let root = std::env::temp_dir().join(format!("simple-http-server-test-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+    return root;
+}
+
+fn create_server(root: &Path, iomode: IoMode) -> Server {
+    let staticfiles = StaticFiles::new(root.to_str().unwrap(), SymlinkPolicy::default()).unwrap();
+    let router = Router::new()
+        .get("/hello/{name}", get_greeting)
+        .post("/hello/{name}", get_greeting)
+        .fallback(staticfiles);
+
+    return Server::builder()
+        .bind("127.0.0.1:0")
+        .threads(2)
+        .iomode(iomode)
+        .graceperiod(Duration::from_secs(1))
+        .handler(router)
+        .build()
+        .unwrap();
+}
+
+fn connect(address: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    return stream;
+}
+
+//  Reads one response framed by Content-Length, leaving the connection ready for the next
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Reply {
+    let mut statusline = String::new();
+    reader.read_line(&mut statusline).unwrap();
+    let status = statusline.split(' ').nth(1).and_then(|status| status.parse::<u16>().ok()).unwrap_or_else(|| panic!("Invalid status line {:?}", statusline));
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() { break; }
+
+        let (name, value) = line.split_once(':').unwrap();
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+
+    let mut reply = Reply { status, headers, body: Vec::new() };
+    let length = reply.get_header("Content-Length").map(|length| length.parse::<usize>().unwrap()).unwrap_or_default();
+    reply.body = vec![0; length];
+    reader.read_exact(&mut reply.body).unwrap();
+
+    return reply;
+}
+
+fn send(stream: &mut TcpStream, request: &str) {
+    stream.write_all(request.as_bytes()).unwrap();
+}
+
+//  Waits for the server thread, failing the test rather than hanging it when the server does not stop
+fn wait_for(thread: thread::JoinHandle<()>) {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        thread.join().unwrap();
+        let _ = sender.send(());
+    });
+
+    receiver.recv_timeout(TIMEOUT).expect("Server did not shut down");
+}
+
+fn serves_routes_and_files(iomode: IoMode) {
+    let root = create_root();
+    let server = create_server(&root, iomode);
+    let address = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let thread = thread::spawn(move || server.run());
+
+    let mut stream = connect(address);
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+    //  Several requests on one persistent connection, the second with a body
+    send(&mut stream, "GET /hello/world HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let reply = read_reply(&mut reader);
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.body, b"hello world from GET");
+    assert!(reply.get_header("Date").is_some());
+
+    send(&mut stream, "POST /hello/there HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nbody");
+    let reply = read_reply(&mut reader);
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.body, b"hello there from POST");
+
+    send(&mut stream, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let reply = read_reply(&mut reader);
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.get_header("Content-Type"), Some("text/html; charset=utf-8"));
+    assert_eq!(reply.body, b"<h1>home</h1>");
+
+    send(&mut stream, "DELETE /hello/world HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let reply = read_reply(&mut reader);
+    assert_eq!(reply.status, 405);
+    assert_eq!(reply.get_header("Allow"), Some("GET, POST, HEAD, OPTIONS"));
+
+    send(&mut stream, "GET /missing.txt HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(read_reply(&mut reader).status, 404);
+
+    send(&mut stream, "GET / HTTP/1.1\r\n\r\n");
+    assert_eq!(read_reply(&mut reader).status, 400);
+
+    shutdown.shutdown();
+    wait_for(thread);
+
+    //  The listener is gone once the server has stopped
+    assert!(TcpStream::connect_timeout(&address, Duration::from_secs(1)).is_err());
+    fs::remove_dir_all(&root).unwrap();
+}
+
+fn finishes_requests_in_flight_on_shutdown(iomode: IoMode) {
+    let root = create_root();
+    let server = create_server(&root, iomode);
+    let address = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let thread = thread::spawn(move || server.run());
+
+    //  Half a request is in flight when the shutdown is asked for, the rest arrives during the grace period
+    let mut stream = connect(address);
+    send(&mut stream, "POST /hello/late HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nhalf");
+    thread::sleep(Duration::from_millis(200));
+    shutdown.shutdown();
+    thread::sleep(Duration::from_millis(200));
+    send(&mut stream, "-there");
+
+    let reply = read_reply(&mut BufReader::new(stream.try_clone().unwrap()));
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.body, b"hello late from POST");
+    assert_eq!(reply.get_header("Connection"), Some("close"));
+
+    wait_for(thread);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+fn times_out_slow_and_idle_connections(iomode: IoMode) {
+    let root = create_root();
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .root(root.to_str().unwrap())
+        .iomode(iomode)
+        .headertimeout(Duration::from_secs(1))
+        .keepalivetimeout(Duration::from_secs(1))
+        .build()
+        .unwrap();
+    let address = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let thread = thread::spawn(move || server.run());
+
+    //  One client stops halfway through a request head, the other goes quiet after its first request
+    let mut slow = connect(address);
+    send(&mut slow, "GET / HTTP/1.1\r\nHost: local");
+    let mut idle = connect(address);
+    send(&mut idle, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let mut idlereader = BufReader::new(idle.try_clone().unwrap());
+    assert_eq!(read_reply(&mut idlereader).status, 200);
+
+    let reply = read_reply(&mut BufReader::new(slow.try_clone().unwrap()));
+    assert_eq!(reply.status, 408);
+    assert_eq!(reply.get_header("Connection"), Some("close"));
+
+    //  Closed without a response
+    assert_eq!(idlereader.read(&mut [0; 16]).unwrap(), 0);
+
+    shutdown.shutdown();
+    wait_for(thread);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+fn lets_the_client_read_why_its_upload_was_refused(iomode: IoMode) {
+    let root = create_root();
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .root(root.to_str().unwrap())
+        .iomode(iomode)
+        .limits(RequestLimits { maxbodysize: 1024, ..RequestLimits::default() })
+        .build()
+        .unwrap();
+    let address = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let thread = thread::spawn(move || server.run());
+
+    //  The whole body is sent before looking for a response, as a simple client would
+    let length = 8 * 1024 * 1024;
+    let mut stream = connect(address);
+    send(&mut stream, &format!("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n", length));
+    stream.write_all(&vec![b'x'; length]).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let reply = read_reply(&mut reader);
+    assert_eq!(reply.status, 413);
+    assert_eq!(reply.get_header("Connection"), Some("close"));
+    assert_eq!(reader.read(&mut [0; 16]).unwrap(), 0);
+
+    shutdown.shutdown();
+    wait_for(thread);
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn serves_routes_and_files_threaded() {
+    serves_routes_and_files(IoMode::Threaded);
+}
+
+#[test]
+fn serves_routes_and_files_on_event_loops() {
+    serves_routes_and_files(IoMode::EventLoop);
+}
+
+#[test]
+fn finishes_requests_in_flight_on_shutdown_threaded() {
+    finishes_requests_in_flight_on_shutdown(IoMode::Threaded);
+}
+
+#[test]
+fn finishes_requests_in_flight_on_shutdown_on_event_loops() {
+    finishes_requests_in_flight_on_shutdown(IoMode::EventLoop);
+}
+
+#[test]
+fn times_out_slow_and_idle_connections_threaded() {
+    times_out_slow_and_idle_connections(IoMode::Threaded);
+}
+
+#[test]
+fn times_out_slow_and_idle_connections_on_event_loops() {
+    times_out_slow_and_idle_connections(IoMode::EventLoop);
+}
+
+#[test]
+fn lets_the_client_read_why_its_upload_was_refused_threaded() {
+    lets_the_client_read_why_its_upload_was_refused(IoMode::Threaded);
+}
+
+#[test]
+fn lets_the_client_read_why_its_upload_was_refused_on_event_loops() {
+    lets_the_client_read_why_its_upload_was_refused(IoMode::EventLoop);
+}
+
+#[test]
+fn spawns_on_an_ephemeral_port() {
+    let root = create_root();
+    let handle = Server::builder().bind("127.0.0.1:0").root(root.to_str().unwrap()).build().unwrap().spawn().unwrap();
+    assert_ne!(handle.address().port(), 0);
+
+    let mut stream = connect(handle.address());
+    send(&mut stream, "GET /index.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    let reply = read_reply(&mut BufReader::new(stream.try_clone().unwrap()));
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.body, b"<h1>home</h1>");
+
+    handle.shutdown();
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn sheds_connections_with_a_retry_after_when_overloaded() {
+    let root = create_root();
+    let server = Server::builder()
+        .bind("127.0.0.1:0")
+        .root(root.to_str().unwrap())
+        .threads(1)
+        .queuecapacity(1)
+        .overloadpolicy(OverloadPolicy::Reject)
+        .retryafter(Duration::from_secs(7))
+        .graceperiod(Duration::from_secs(1))
+        .build()
+        .unwrap();
+    let address = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let thread = thread::spawn(move || server.run());
+
+    //  The only worker keeps the first connection, the second waits in the queue
+    let mut first = connect(address);
+    send(&mut first, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(read_reply(&mut BufReader::new(first.try_clone().unwrap())).status, 200);
+    let _queued = connect(address);
+    thread::sleep(Duration::from_millis(200));
+
+    //  Shed as soon as it is accepted, before sending anything
+    let shed = connect(address);
+    let reply = read_reply(&mut BufReader::new(shed.try_clone().unwrap()));
+    assert_eq!(reply.status, 503);
+    assert_eq!(reply.get_header("Retry-After"), Some("7"));
+    assert_eq!(reply.get_header("Connection"), Some("close"));
+
+    shutdown.shutdown();
+    wait_for(thread);
+    fs::remove_dir_all(&root).unwrap();
+}